@@ -0,0 +1,102 @@
+//! The accept side of the control socket, kept alive through fd exhaustion
+//! and a broken listener.
+
+use log::{error, info, warn};
+use std::fs::File;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{paths, ACCEPT_BACKOFF_MS, REBIND_TRIES};
+use crate::stats;
+
+const ENFILE: i32 = 23;
+const EMFILE: i32 = 24;
+const EINTR: i32 = 4;
+const ECONNABORTED: i32 = 103;
+const EPROTO: i32 = 71;
+
+pub struct Acceptor {
+    listener: UnixListener,
+    /// Spare fd given up when out of fds, so the daemon gets one to work with.
+    reserve: Option<File>,
+    /// Out of fds since the last successful accept; logged once per episode.
+    starved: bool,
+}
+
+fn reserve() -> Option<File> {
+    File::open("/dev/null").ok()
+}
+
+impl Acceptor {
+    pub fn new(listener: UnixListener) -> Self {
+        Self {
+            listener,
+            reserve: reserve(),
+            starved: false,
+        }
+    }
+
+    /// The next client connection; errors are handled here rather than
+    /// surfaced, the daemon has nothing better to do than keep accepting.
+    pub fn accept(&mut self) -> UnixStream {
+        loop {
+            let e = match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if self.starved {
+                        info!("socket: accepting again");
+                        self.starved = false;
+                    }
+                    if self.reserve.is_none() {
+                        self.reserve = reserve();
+                    }
+                    return stream;
+                }
+                Err(e) => e,
+            };
+
+            match e.raw_os_error() {
+                Some(EMFILE | ENFILE) => {
+                    stats::ACCEPT_FD_ERRORS.fetch_add(1, Ordering::Relaxed);
+                    if !self.starved {
+                        warn!("socket: bad accept: {}, releasing the reserve fd", e);
+                        self.starved = true;
+                    }
+                    self.reserve.take();
+                    thread::sleep(Duration::from_millis(ACCEPT_BACKOFF_MS));
+                }
+                Some(EINTR | ECONNABORTED | EPROTO) => {
+                    stats::ACCEPT_TRANSIENT_ERRORS.fetch_add(1, Ordering::Relaxed);
+                }
+                _ => {
+                    stats::ACCEPT_FATAL_ERRORS.fetch_add(1, Ordering::Relaxed);
+                    error!("socket: bad accept: {}, rebinding {}", e, &paths().socket);
+                    self.rebind();
+                }
+            }
+        }
+    }
+
+    fn rebind(&mut self) {
+        for _ in 0..REBIND_TRIES {
+            let _ = std::fs::remove_file(&paths().socket);
+            match UnixListener::bind(&paths().socket) {
+                Ok(listener) => {
+                    info!("socket: rebound {}", &paths().socket);
+                    self.listener = listener;
+                    return;
+                }
+                Err(e) => warn!("socket: bad rebind {}: {}", &paths().socket, e),
+            }
+            thread::sleep(Duration::from_millis(ACCEPT_BACKOFF_MS));
+        }
+
+        error!(
+            "socket: listener lost, giving up after {} rebinds",
+            REBIND_TRIES
+        );
+        log::logger().flush();
+        std::process::exit(1);
+    }
+}
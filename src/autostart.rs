@@ -0,0 +1,333 @@
+//! The autostart file: names of the services started with the daemon, one
+//! per line. Without the file every configured service is started.
+//!
+//! People edit the file by hand too, over adb while the daemon runs, so a
+//! rewrite only replaces the content it started from: if the file changed
+//! in the meantime the change is applied again to what is there now. The
+//! content replaced last is kept in `AUTOSTART_PATH.bak`.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{ErrorKind, Write};
+
+use log::warn;
+
+use crate::config::{paths, AUTOSTART_TRIES};
+use crate::stats;
+
+/// The file operations of a rewrite, so tests can fail any one of them.
+trait Disk {
+    fn read(&self, path: &str) -> std::io::Result<String>;
+    /// Creates `path` with `contents`, synced.
+    fn create(&self, path: &str, contents: &str) -> std::io::Result<()>;
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()>;
+    fn remove(&self, path: &str);
+}
+
+struct Real;
+
+impl Disk for Real {
+    fn read(&self, path: &str) -> std::io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn create(&self, path: &str, contents: &str) -> std::io::Result<()> {
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()
+    }
+
+    fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &str) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// The file's content, None if there is no autostart file.
+fn contents(disk: &impl Disk, path: &str) -> Option<String> {
+    disk.read(path).ok()
+}
+
+fn parse(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn hash(contents: &Option<String>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The enabled names in file order, None if there is no autostart file.
+pub fn read() -> Option<Vec<String>> {
+    contents(&Real, &paths().autostart).as_deref().map(parse)
+}
+
+pub fn render(names: &[String]) -> String {
+    names.iter().map(|name| format!("{}\n", name)).collect()
+}
+
+/// Runs `change` on the enabled names (None without a file) and writes the
+/// names it returns, if any. Should the file change between the read and
+/// the write, `change` runs again on the new content, AUTOSTART_TRIES times
+/// at most. Returns what the last run of `change` returned.
+pub fn update<T>(
+    change: impl FnMut(Option<Vec<String>>) -> (Option<Vec<String>>, T),
+) -> Result<T, String> {
+    update_on(&Real, &paths().autostart, change)
+}
+
+fn update_on<T>(
+    disk: &impl Disk,
+    path: &str,
+    mut change: impl FnMut(Option<Vec<String>>) -> (Option<Vec<String>>, T),
+) -> Result<T, String> {
+    for _ in 0..AUTOSTART_TRIES {
+        let seen = contents(disk, path);
+        let (names, result) = change(seen.as_deref().map(parse));
+        let Some(names) = names else {
+            return Ok(result);
+        };
+        if write(disk, path, &names, hash(&seen))? {
+            return Ok(result);
+        }
+        warn!("autostart: {} changed while updating, again", path);
+    }
+    Err(format!(
+        "autostart: {} kept changing, gave up after {} tries",
+        path, AUTOSTART_TRIES
+    ))
+}
+
+/// Replaces the file in one rename, so a crash leaves the old or the new
+/// list, unless its content no longer hashes to `seen`; false then. The
+/// temporary file never outlives the call.
+fn write(disk: &impl Disk, path: &str, names: &[String], seen: u64) -> Result<bool, String> {
+    let tmp = format!("{}.tmp", path);
+    let written = disk.create(&tmp, &render(names)).and_then(|_| {
+        // as late as possible, the rename is the only step left
+        let current = contents(disk, path);
+        if hash(&current) != seen {
+            return Ok(false);
+        }
+        if let Some(current) = current {
+            disk.create(&format!("{}.bak", path), &current)?;
+        }
+        disk.rename(&tmp, path)?;
+        Ok(true)
+    });
+
+    match stats::track_write(written) {
+        Ok(true) => Ok(true),
+        Ok(false) => {
+            disk.remove(&tmp);
+            Ok(false)
+        }
+        Err(e) => {
+            disk.remove(&tmp);
+            match e.kind() {
+                ErrorKind::ReadOnlyFilesystem => Err(format!(
+                    "filesystem read-only: cannot update autostart {}",
+                    path
+                )),
+                _ => Err(format!("autostart: bad write {}: {}", path, e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::Error;
+    use std::path::Path;
+
+    use crate::stats::EROFS;
+
+    const ENOSPC: i32 = 28;
+
+    /// The real disk with failures injected: `fail` breaks the operation
+    /// named on the file with that suffix, and each temp file races an
+    /// editor writing the next of `edits` to the target while they last.
+    struct Faulty {
+        fail: Option<(&'static str, &'static str, i32)>,
+        edits: RefCell<Vec<&'static str>>,
+    }
+
+    impl Faulty {
+        fn new() -> Self {
+            Self {
+                fail: None,
+                edits: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn check(&self, op: &str, path: &str) -> std::io::Result<()> {
+            match self.fail {
+                Some((failing, suffix, errno)) if failing == op && path.ends_with(suffix) => {
+                    Err(Error::from_raw_os_error(errno))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl Disk for Faulty {
+        fn read(&self, path: &str) -> std::io::Result<String> {
+            Real.read(path)
+        }
+
+        fn create(&self, path: &str, contents: &str) -> std::io::Result<()> {
+            if path.ends_with(".tmp") && !self.edits.borrow().is_empty() {
+                let edit = self.edits.borrow_mut().remove(0);
+                Real.create(path.trim_end_matches(".tmp"), edit)?;
+            }
+            // a failed create leaves what it got to, as a full disk would
+            Real.create(path, &contents[..contents.len() / 2])?;
+            self.check("create", path)?;
+            Real.create(path, contents)
+        }
+
+        fn rename(&self, from: &str, to: &str) -> std::io::Result<()> {
+            self.check("rename", to)?;
+            Real.rename(from, to)
+        }
+
+        fn remove(&self, path: &str) {
+            Real.remove(path)
+        }
+    }
+
+    /// An autostart file in a directory of its own, removed on drop.
+    struct Scratch(String);
+
+    impl Scratch {
+        fn path(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            if let Some(dir) = Path::new(&self.0).parent() {
+                let _ = std::fs::remove_dir_all(dir);
+            }
+        }
+    }
+
+    /// A fresh autostart file holding `contents`.
+    fn file(test: &str, contents: &str) -> Scratch {
+        let dir =
+            std::env::temp_dir().join(format!("dctl-autostart-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("autostart").to_string_lossy().to_string();
+        std::fs::write(&path, contents).unwrap();
+        Scratch(path)
+    }
+
+    fn enable(name: &'static str) -> impl FnMut(Option<Vec<String>>) -> (Option<Vec<String>>, u32) {
+        let mut runs = 0;
+        move |names| {
+            runs += 1;
+            let mut names = names.unwrap_or_default();
+            names.push(name.to_string());
+            (Some(names), runs)
+        }
+    }
+
+    fn read(path: &str) -> String {
+        std::fs::read_to_string(path).unwrap_or_default()
+    }
+
+    #[test]
+    fn update_keeps_the_old_content_in_bak() {
+        let scratch = file("bak", "a\n");
+        let path = scratch.path();
+        assert_eq!(update_on(&Real, path, enable("b")), Ok(1));
+        assert_eq!(read(path), "a\nb\n");
+        assert_eq!(read(&format!("{}.bak", path)), "a\n");
+
+        // the backup restores the list from before the update
+        std::fs::rename(format!("{}.bak", path), path).unwrap();
+        assert_eq!(
+            contents(&Real, path).as_deref().map(parse),
+            Some(vec![String::from("a")])
+        );
+    }
+
+    #[test]
+    fn read_only_target_changes_nothing() {
+        for op in ["create", "rename"] {
+            let scratch = file(op, "a\n");
+            let path = scratch.path();
+            let disk = Faulty {
+                fail: Some((op, if op == "create" { ".tmp" } else { "autostart" }, EROFS)),
+                ..Faulty::new()
+            };
+            let e = update_on(&disk, path, enable("b")).unwrap_err();
+            assert!(
+                e.starts_with("filesystem read-only: cannot update autostart"),
+                "{}",
+                e
+            );
+            assert_eq!(read(path), "a\n");
+            assert!(
+                !Path::new(&format!("{}.tmp", path)).exists(),
+                "{} left the temp file",
+                op
+            );
+        }
+        stats::track_write(Ok(())).unwrap();
+    }
+
+    #[test]
+    fn failed_backup_keeps_the_target() {
+        let scratch = file("bad-bak", "a\n");
+        let path = scratch.path();
+        let disk = Faulty {
+            fail: Some(("create", ".bak", ENOSPC)),
+            ..Faulty::new()
+        };
+        let e = update_on(&disk, path, enable("b")).unwrap_err();
+        assert!(e.starts_with("autostart: bad write"), "{}", e);
+        assert_eq!(read(path), "a\n");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+    }
+
+    #[test]
+    fn concurrent_edit_is_applied_again() {
+        let scratch = file("race", "a\n");
+        let path = scratch.path();
+        let disk = Faulty {
+            edits: RefCell::new(vec!["a\nc\n"]),
+            ..Faulty::new()
+        };
+        assert_eq!(update_on(&disk, path, enable("b")), Ok(2));
+        assert_eq!(read(path), "a\nc\nb\n");
+        assert_eq!(read(&format!("{}.bak", path)), "a\nc\n");
+    }
+
+    #[test]
+    fn endless_edits_give_up() {
+        let scratch = file("edits", "a\n");
+        let path = scratch.path();
+        let disk = Faulty {
+            edits: RefCell::new(vec!["x\n", "y\n", "z\n"]),
+            ..Faulty::new()
+        };
+        let e = update_on(&disk, path, enable("b")).unwrap_err();
+        assert!(e.contains("kept changing"), "{}", e);
+        assert_eq!(read(path), "z\n");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+    }
+}
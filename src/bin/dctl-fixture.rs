@@ -0,0 +1,106 @@
+//! A stand-in service for the integration tests and for trying the daemon
+//! out in a `--sandbox`: what it does is given by flags, so one binary
+//! covers the behaviours a supervisor has to cope with.
+//!
+//! `--exit-after=SECS` exits after that long (forever without it) with
+//! `--code=N`; `--ignore-term` ignores SIGTERM; `--fork=PIDFILE` leaves a
+//! child behind holding stdout, its pid in PIDFILE; `--print-rate=N` writes
+//! N lines a second to stdout; `--watchdog=PATH` rewrites PATH with a rising
+//! counter every tick; `--fds=PATH` writes the fds it was started with to
+//! PATH.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TICK_MS: u64 = 50;
+/// Lifetime of a `--fork` child, so a failed test leaves nothing for long.
+const CHILD_SEC: u64 = 30;
+const SIGTERM: i32 = 15;
+const SIG_IGN: usize = 1;
+
+extern "C" {
+    fn signal(signum: i32, handler: usize) -> usize;
+}
+
+fn main() {
+    let flags: Vec<String> = std::env::args().skip(1).collect();
+    let value = |name: &str| {
+        flags
+            .iter()
+            .find_map(|flag| flag.strip_prefix(name)?.strip_prefix('='))
+    };
+    let flag = |name: &str| flags.iter().any(|flag| flag == name);
+
+    let exit_after = value("--exit-after").and_then(|secs| secs.parse::<f64>().ok());
+    let code = value("--code")
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0);
+    let rate = value("--print-rate").and_then(|rate| rate.parse::<u32>().ok());
+    let watchdog = value("--watchdog");
+
+    // before anything here opens an fd
+    if let Some(path) = value("--fds") {
+        fds(path);
+    }
+
+    if flag("--ignore-term") {
+        unsafe { signal(SIGTERM, SIG_IGN) };
+    }
+    if let Some(pidfile) = value("--fork") {
+        // left running on purpose: it is the orphan a stop has to cope with
+        #[allow(clippy::zombie_processes)]
+        let child = Command::new(std::env::current_exe().expect("fixture: no current exe"))
+            .arg(format!("--exit-after={}", CHILD_SEC))
+            .stdin(Stdio::null())
+            .spawn()
+            .expect("fixture: bad fork");
+        std::fs::write(pidfile, child.id().to_string()).expect("fixture: bad pidfile");
+    }
+
+    let start = Instant::now();
+    let mut printed: u64 = 0;
+    let mut ticks: u64 = 0;
+    loop {
+        if exit_after.is_some_and(|secs| start.elapsed().as_secs_f64() >= secs) {
+            std::process::exit(code);
+        }
+        if let Some(rate) = rate {
+            let due = (start.elapsed().as_secs_f64() * rate as f64) as u64;
+            let mut stdout = std::io::stdout().lock();
+            while printed < due {
+                printed += 1;
+                let _ = writeln!(stdout, "fixture: line {}", printed);
+            }
+            let _ = stdout.flush();
+        }
+        if let Some(path) = watchdog {
+            ticks += 1;
+            let _ = std::fs::write(path, ticks.to_string());
+        }
+        thread::sleep(Duration::from_millis(TICK_MS));
+    }
+}
+
+/// `N TARGET` for each open fd, written whole to `path` by a rename.
+fn fds(path: &str) {
+    let mut lines = Vec::new();
+    let listing = std::fs::read_dir("/proc/self/fd").expect("fixture: bad read /proc/self/fd");
+    for entry in listing.flatten() {
+        let target = std::fs::read_link(entry.path()).unwrap_or_default();
+        // the listing's own fd
+        if target.starts_with("/proc") && target.ends_with("fd") {
+            continue;
+        }
+        lines.push(format!(
+            "{} {}\n",
+            entry.file_name().to_string_lossy(),
+            target.display()
+        ));
+    }
+
+    let partial = format!("{}.partial", path);
+    std::fs::write(&partial, lines.concat()).expect("fixture: bad fds");
+    std::fs::rename(partial, path).expect("fixture: bad fds");
+}
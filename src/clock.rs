@@ -0,0 +1,188 @@
+//! Wall clock steps, e.g. NTP syncing a bogus boot clock hours forward.
+//!
+//! Every duration the daemon acts on (uptime, backoff, heartbeats, timeouts)
+//! is measured on `Instant`, which is monotonic; the wall clock only stamps
+//! `daemon#events`. A step is spotted by comparing how far both clocks moved
+//! between two checks.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::{CLOCK_STEP_SEC, RETRY_MAX_SEC};
+
+/// Where `Watch` reads the time: the system, or a fake one in tests.
+pub trait Clock {
+    fn wall(&self) -> SystemTime;
+    fn mono(&self) -> Instant;
+}
+
+pub struct System;
+
+impl Clock for System {
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn mono(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+pub struct Watch<C: Clock = System> {
+    clock: C,
+    wall: SystemTime,
+    mono: Instant,
+}
+
+fn secs(time: SystemTime) -> f64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs_f64(),
+        Err(before) => -before.duration().as_secs_f64(),
+    }
+}
+
+impl Watch {
+    pub fn new() -> Self {
+        Watch::with(System)
+    }
+}
+
+impl<C: Clock> Watch<C> {
+    pub fn with(clock: C) -> Self {
+        Self {
+            wall: clock.wall(),
+            mono: clock.mono(),
+            clock,
+        }
+    }
+
+    /// Seconds the wall clock jumped since the last check, if more than
+    /// CLOCK_STEP_SEC either way.
+    pub fn step(&mut self) -> Option<i64> {
+        let (wall, mono) = (self.clock.wall(), self.clock.mono());
+        let drift = (secs(wall) - secs(self.wall)) - mono.duration_since(self.mono).as_secs_f64();
+        (self.wall, self.mono) = (wall, mono);
+
+        match drift.abs() > CLOCK_STEP_SEC as f64 {
+            true => Some(drift.round() as i64),
+            false => None,
+        }
+    }
+}
+
+/// Seconds before respawn number `attempt` (from 1) of a service crashing
+/// quickly: RestartSec doubled per attempt, capped at RETRY_MAX_SEC unless
+/// RestartSec alone is longer.
+pub fn backoff(restart_sec: u64, attempt: u32) -> u64 {
+    let doubled = restart_sec.checked_shl(attempt - 1).unwrap_or(u64::MAX);
+    doubled.min(RETRY_MAX_SEC.max(restart_sec))
+}
+
+/// An even share of what is left until `deadline` among `left` stops.
+pub fn share(deadline: Instant, now: Instant, left: u32) -> Duration {
+    deadline.saturating_duration_since(now) / left.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Both clocks, moved by hand.
+    #[derive(Clone)]
+    struct Fake {
+        wall: Rc<Cell<SystemTime>>,
+        mono: Rc<Cell<Instant>>,
+    }
+
+    impl Fake {
+        fn new() -> Self {
+            Self {
+                wall: Rc::new(Cell::new(UNIX_EPOCH + Duration::from_secs(1_000_000))),
+                mono: Rc::new(Cell::new(Instant::now())),
+            }
+        }
+
+        /// Time passing: both clocks move on.
+        fn pass(&self, secs: u64) {
+            self.wall.set(self.wall.get() + Duration::from_secs(secs));
+            self.mono.set(self.mono.get() + Duration::from_secs(secs));
+        }
+
+        /// The wall clock alone is set, forward for positive `secs`.
+        fn set_wall(&self, secs: i64) {
+            let step = Duration::from_secs(secs.unsigned_abs());
+            self.wall.set(match secs > 0 {
+                true => self.wall.get() + step,
+                false => self.wall.get() - step,
+            });
+        }
+    }
+
+    impl Clock for Fake {
+        fn wall(&self) -> SystemTime {
+            self.wall.get()
+        }
+
+        fn mono(&self) -> Instant {
+            self.mono.get()
+        }
+    }
+
+    #[test]
+    fn time_passing_is_no_step() {
+        let fake = Fake::new();
+        let mut watch = Watch::with(fake.clone());
+        fake.pass(3600);
+        assert_eq!(watch.step(), None);
+    }
+
+    #[test]
+    fn steps_either_way_are_reported_once() {
+        let fake = Fake::new();
+        let mut watch = Watch::with(fake.clone());
+
+        fake.pass(10);
+        fake.set_wall(3 * 3600);
+        assert_eq!(watch.step(), Some(3 * 3600));
+        fake.pass(10);
+        assert_eq!(watch.step(), None);
+
+        fake.set_wall(-7200);
+        assert_eq!(watch.step(), Some(-7200));
+    }
+
+    #[test]
+    fn drift_within_the_threshold_is_no_step() {
+        let fake = Fake::new();
+        let mut watch = Watch::with(fake.clone());
+        fake.set_wall(CLOCK_STEP_SEC as i64);
+        assert_eq!(watch.step(), None);
+        fake.set_wall(CLOCK_STEP_SEC as i64 + 1);
+        assert_eq!(watch.step(), Some(CLOCK_STEP_SEC as i64 + 1));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let waits: Vec<u64> = (1..=7).map(|attempt| backoff(1, attempt)).collect();
+        assert_eq!(waits, [1, 2, 4, 8, 16, RETRY_MAX_SEC, RETRY_MAX_SEC]);
+        assert_eq!(backoff(0, 4), 0);
+        // a RestartSec above the cap is kept, not cut down
+        assert_eq!(backoff(RETRY_MAX_SEC * 2, 3), RETRY_MAX_SEC * 2);
+        assert_eq!(backoff(1, 200), RETRY_MAX_SEC);
+    }
+
+    #[test]
+    fn deadlines_ignore_wall_clock_steps() {
+        let fake = Fake::new();
+        let deadline = fake.mono() + Duration::from_secs(30);
+        fake.set_wall(-86_400);
+        assert_eq!(share(deadline, fake.mono(), 3), Duration::from_secs(10));
+
+        fake.pass(12);
+        fake.set_wall(86_400);
+        assert_eq!(share(deadline, fake.mono(), 2), Duration::from_secs(9));
+        fake.pass(60);
+        assert_eq!(share(deadline, fake.mono(), 1), Duration::ZERO);
+    }
+}
@@ -1,9 +1,17 @@
+use std::sync::OnceLock;
+
 #[cfg(target_os = "android")]
 pub const SOCKET_PATH: &str = "/data/daemon/daemon.sock";
 #[cfg(target_os = "android")]
 pub const CONFIG_PATH: &str = "/data/daemon/config";
 #[cfg(target_os = "android")]
 pub const LOG_PATH: &str = "/data/daemon/daemon.log";
+#[cfg(target_os = "android")]
+pub const RUNTIME_PATH: &str = "/data/daemon/run";
+#[cfg(target_os = "android")]
+pub const AUTOSTART_PATH: &str = "/data/daemon/autostart";
+#[cfg(target_os = "android")]
+pub const SERVICE_LOG_PATH: &str = "/data/daemon";
 
 #[cfg(target_os = "linux")]
 pub const SOCKET_PATH: &str = "/tmp/daemon.sock";
@@ -11,5 +19,314 @@ pub const SOCKET_PATH: &str = "/tmp/daemon.sock";
 pub const CONFIG_PATH: &str = "/tmp/config";
 #[cfg(target_os = "linux")]
 pub const LOG_PATH: &str = "/tmp/daemon.log";
+#[cfg(target_os = "linux")]
+pub const RUNTIME_PATH: &str = "/tmp/dctl-run";
+#[cfg(target_os = "linux")]
+pub const AUTOSTART_PATH: &str = "/tmp/autostart";
+#[cfg(target_os = "linux")]
+pub const SERVICE_LOG_PATH: &str = "/tmp/dctl-log";
+
+/// Where the daemon and client keep their files: the paths above, or all of
+/// them under one directory with `--sandbox=DIR`.
+pub struct Paths {
+    pub socket: String,
+    pub config: String,
+    pub log: String,
+    pub runtime: String,
+    pub autostart: String,
+    pub service_log: String,
+}
+
+static PATHS: OnceLock<Paths> = OnceLock::new();
+
+pub fn paths() -> &'static Paths {
+    PATHS.get_or_init(|| Paths {
+        socket: SOCKET_PATH.to_string(),
+        config: CONFIG_PATH.to_string(),
+        log: LOG_PATH.to_string(),
+        runtime: RUNTIME_PATH.to_string(),
+        autostart: AUTOSTART_PATH.to_string(),
+        service_log: SERVICE_LOG_PATH.to_string(),
+    })
+}
+
+/// Puts every path of `Paths` under `dir`, for a daemon that must not
+/// touch the real ones; only effective before the first `paths()`.
+pub fn sandbox(dir: &str) {
+    let dir = dir.trim_end_matches('/');
+    let _ = PATHS.set(Paths {
+        socket: format!("{}/daemon.sock", dir),
+        config: format!("{}/config", dir),
+        log: format!("{}/daemon.log", dir),
+        runtime: format!("{}/run", dir),
+        autostart: format!("{}/autostart", dir),
+        service_log: format!("{}/log", dir),
+    });
+}
 
+/// One sandbox for every unit test of the crate that touches paths, set
+/// before any of them asks for `paths()`.
+#[cfg(test)]
+pub fn test_paths() -> &'static Paths {
+    let dir = std::env::temp_dir().join(format!("dctl-unit-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    sandbox(&dir.to_string_lossy());
+    paths()
+}
+
+/// Where `NETNS=name` finds its namespace, as `ip netns add` makes them.
+pub const NETNS_PATH: &str = "/run/netns";
+
+/// Runs shorter than this count as a crash; also the first retry backoff
+/// of services without `RestartSec=`.
 pub const RESTART_SEC: u64 = 1;
+/// Consecutive quick crashes after which a service is left failed.
+pub const START_LIMIT: u32 = 5;
+/// Upper bound of the doubling retry backoff.
+pub const RETRY_MAX_SEC: u64 = 30;
+/// The stop signal is followed by SIGKILL if the service is still up after
+/// this, unless it sets `TIMEOUTSTOP=secs`.
+pub const STOP_TIMEOUT_SEC: u64 = 10;
+/// Bound on stopping everything at daemon exit; survivors get SIGKILL.
+pub const SHUTDOWN_TIMEOUT_SEC: u64 = 30;
+/// Longest any one service gets to stop at daemon exit, whatever its
+/// TIMEOUTSTOP says.
+pub const SHUTDOWN_STOP_TIMEOUT_SEC: u64 = 10;
+/// How often a stop checks whether the supervisor has wound down.
+pub const STOP_POLL_MS: u64 = 20;
+
+/// Terminates every daemon response.
+pub const END_MARKER: char = '\u{4}';
+
+/// Seconds between attempts to reopen LOG_PATH after falling back to stderr.
+pub const LOG_RETRY_SEC: u64 = 5;
+/// Records waiting for the log writer; more are dropped and counted.
+pub const LOG_QUEUE: usize = 1024;
+/// The writer goes to the file once a batch reaches this many bytes, or
+/// when the queue ran empty.
+pub const LOG_BATCH: usize = 64 * 1024;
+/// Bound on flushing the log queue at daemon exit.
+pub const LOG_FLUSH_SEC: u64 = 2;
+pub const LOG_FLUSH_POLL_MS: u64 = 10;
+
+/// Request tracing switches itself off after this many seconds.
+pub const TRACE_SEC: u64 = 600;
+/// Traced payloads are cut after this many characters.
+pub const TRACE_MAX: usize = 512;
+
+/// Supervisors poll their child this often (and heartbeat while doing so).
+pub const SUPERVISE_TICK_MS: u64 = 200;
+/// A running service whose supervisor was silent this long is marked failed.
+pub const HEARTBEAT_SEC: u64 = 5;
+/// Interval of the daemon-side supervisor sweep.
+pub const SWEEP_SEC: u64 = 10;
+
+/// Sustained output lines per second let through per service.
+pub const LOG_RATE: u64 = 1000;
+/// Lines a service may print in a burst above LOG_RATE.
+pub const LOG_BURST: u64 = 5000;
+/// Dropped lines are summed up once per this many seconds.
+pub const LOG_SUMMARY_SEC: u64 = 10;
+
+/// Completed idempotency keys remembered at most.
+pub const IDEM_MAX: usize = 256;
+/// Seconds a completed idempotency key is remembered.
+pub const IDEM_TTL_SEC: u64 = 600;
+
+/// Seconds between checks for a vanished backing mount to come back.
+pub const MOUNT_RECHECK_SEC: u64 = 1;
+
+/// Permission bits the log and autostart files must at least have, so status
+/// tools running as another uid can read them.
+pub const FILE_MODE: u32 = 0o644;
+/// Permission bits runtime directories must at least have.
+pub const RUNTIME_DIR_MODE: u32 = 0o755;
+
+/// Set in the environment of hook scripts; the client refuses mutating
+/// requests from them unless --allow-reentrant is given.
+pub const HOOK_ENV: &str = "DCTL_IN_HOOK";
+/// Seconds an ONSTART or ONSTOP command may run before it is killed.
+pub const HOOK_TIMEOUT_SEC: u64 = 10;
+
+/// Restarts across all services within an hour at which the system is
+/// reported unstable; 0 never does.
+pub const UNSTABLE_RESTARTS: u64 = 100;
+/// Command run, like a hook, when the system turns unstable, with the
+/// hour's count in DCTL_RESTARTS; empty for none.
+pub const UNSTABLE_HOOK: &str = "";
+
+/// Entries kept in the event history.
+pub const EVENTS_MAX: usize = 256;
+/// Events about the service listed by `why`.
+pub const WHY_EVENTS: usize = 5;
+
+/// Default total seconds `--retry` keeps trying to reach the daemon.
+pub const RETRY_SEC: u64 = 5;
+/// First backoff step of `--retry`, doubled up to a second.
+pub const RETRY_BACKOFF_MS: u64 = 50;
+
+/// Autostarted services allowed to be launching at the same time.
+pub const AUTOSTART_CONCURRENCY: usize = 4;
+/// Times an autostart rewrite is applied again after finding the file
+/// changed under it.
+pub const AUTOSTART_TRIES: u32 = 3;
+
+/// Mutating requests on one service that may be running or waiting their
+/// turn; one more is refused as busy.
+pub const SERVICE_QUEUE_MAX: usize = 8;
+
+/// Longest argv or environment key list kept per service for `status --full`.
+pub const EXECUTED_MAX: usize = 4096;
+/// Percentage of RLIMIT_NOFILE at which `status --full` flags a service's
+/// open fds.
+pub const FD_WARN_PERCENT: u64 = 80;
+/// Services listed by `daemon#fdtop` without `top=N`.
+pub const FDTOP: usize = 10;
+
+/// Seconds a finished `run` service stays listed before the sweep drops it,
+/// unless it was run with `keep`.
+pub const TRANSIENT_RETENTION_SEC: u64 = 3600;
+
+/// Lines `log#name` answers without `lines=N`.
+pub const LOG_TAIL: usize = 100;
+
+/// Longest event or remembered log record kept; longer ones are cut and end
+/// in `…`.
+pub const STORED_MAX: usize = 1024;
+
+/// Warn and error records the logger keeps in memory.
+pub const LOG_RING: usize = 64;
+
+/// Records `--with-errors` asks for when no count is given.
+pub const WITH_ERRORS: usize = 20;
+
+/// Environment variable telling a `Handles=` service which socket to serve.
+pub const EXT_SOCKET_ENV: &str = "DCTL_EXT_SOCKET";
+
+/// How long a forwarded `x-` request may take end to end.
+pub const EXT_TIMEOUT_SEC: u64 = 5;
+
+/// Wall clock jumps beyond this, relative to the monotonic clock, are logged.
+pub const CLOCK_STEP_SEC: u64 = 60;
+
+/// Client wait for verbs the daemon answers right away.
+pub const CLIENT_TIMEOUT_SEC: u64 = 5;
+/// Extra client wait on top of the daemon's own bound for slow verbs.
+pub const CLIENT_MARGIN_SEC: u64 = 5;
+
+/// Env names containing TOKEN, KEY or PASSWORD are masked in output like
+/// the ones `SECRET_ENV=` lists.
+pub const SECRET_HEURISTIC: bool = true;
+
+/// Characters of a `Description=` kept at load time.
+pub const DESCRIPTION_MAX: usize = 200;
+
+pub const KMSG_PATH: &str = "/dev/kmsg";
+/// Copy warn+ records to KMSG_PATH all the time, not only while the log file
+/// is unusable.
+pub const KMSG_LOG: bool = false;
+/// At most KMSG_BURST records go to kmsg per KMSG_INTERVAL_SEC.
+pub const KMSG_BURST: u32 = 10;
+pub const KMSG_INTERVAL_SEC: u64 = 5;
+
+/// Where log records go: `file` (LOG_PATH), `syslog` (SYSLOG_PATH) or
+/// `both`. Android builds have no syslog output and only take `file`.
+pub const LOG_OUTPUT: &str = "file";
+pub const SYSLOG_PATH: &str = "/dev/log";
+/// Messages sent to SYSLOG_PATH are cut to this many bytes; receivers drop
+/// bigger datagrams.
+pub const SYSLOG_MAX: usize = 2048;
+/// A send to a backed-up syslog daemon gives up after this and the record
+/// is dropped.
+pub const SYSLOG_SEND_MS: u64 = 100;
+
+/// Pause after a failed accept before trying again.
+pub const ACCEPT_BACKOFF_MS: u64 = 100;
+/// Attempts at binding the control socket anew before the daemon gives up.
+pub const REBIND_TRIES: u32 = 3;
+
+/// `(name, value)` of every setting above for `daemon#config`, modes in
+/// octal. All are compiled in; the only thing that moves one is
+/// `--sandbox`, and the paths it moves show it in their value.
+pub fn effective() -> Vec<(&'static str, String)> {
+    macro_rules! settings {
+        ($($name:ident),* $(,)?) => {
+            vec![$((stringify!($name), $name.to_string())),*]
+        };
+    }
+    let paths = paths();
+    let mut settings = vec![
+        ("SOCKET_PATH", paths.socket.clone()),
+        ("CONFIG_PATH", paths.config.clone()),
+        ("LOG_PATH", paths.log.clone()),
+        ("RUNTIME_PATH", paths.runtime.clone()),
+        ("AUTOSTART_PATH", paths.autostart.clone()),
+        ("SERVICE_LOG_PATH", paths.service_log.clone()),
+    ];
+    settings.extend(settings![
+        NETNS_PATH,
+        RESTART_SEC,
+        START_LIMIT,
+        RETRY_MAX_SEC,
+        STOP_TIMEOUT_SEC,
+        SHUTDOWN_TIMEOUT_SEC,
+        SHUTDOWN_STOP_TIMEOUT_SEC,
+        STOP_POLL_MS,
+        LOG_RETRY_SEC,
+        LOG_QUEUE,
+        LOG_BATCH,
+        LOG_FLUSH_SEC,
+        LOG_FLUSH_POLL_MS,
+        TRACE_SEC,
+        TRACE_MAX,
+        SUPERVISE_TICK_MS,
+        HEARTBEAT_SEC,
+        SWEEP_SEC,
+        LOG_RATE,
+        LOG_BURST,
+        LOG_SUMMARY_SEC,
+        IDEM_MAX,
+        IDEM_TTL_SEC,
+        MOUNT_RECHECK_SEC,
+        HOOK_ENV,
+        HOOK_TIMEOUT_SEC,
+        UNSTABLE_RESTARTS,
+        UNSTABLE_HOOK,
+        EVENTS_MAX,
+        WHY_EVENTS,
+        RETRY_SEC,
+        RETRY_BACKOFF_MS,
+        AUTOSTART_CONCURRENCY,
+        AUTOSTART_TRIES,
+        SERVICE_QUEUE_MAX,
+        EXECUTED_MAX,
+        FD_WARN_PERCENT,
+        FDTOP,
+        TRANSIENT_RETENTION_SEC,
+        LOG_TAIL,
+        STORED_MAX,
+        LOG_RING,
+        WITH_ERRORS,
+        EXT_SOCKET_ENV,
+        EXT_TIMEOUT_SEC,
+        CLOCK_STEP_SEC,
+        CLIENT_TIMEOUT_SEC,
+        CLIENT_MARGIN_SEC,
+        SECRET_HEURISTIC,
+        DESCRIPTION_MAX,
+        KMSG_PATH,
+        KMSG_LOG,
+        KMSG_BURST,
+        KMSG_INTERVAL_SEC,
+        LOG_OUTPUT,
+        SYSLOG_PATH,
+        SYSLOG_MAX,
+        SYSLOG_SEND_MS,
+        ACCEPT_BACKOFF_MS,
+        REBIND_TRIES,
+    ]);
+    settings.push(("FILE_MODE", format!("{:o}", FILE_MODE)));
+    settings.push(("RUNTIME_DIR_MODE", format!("{:o}", RUNTIME_DIR_MODE)));
+    settings.sort();
+    settings
+}
@@ -0,0 +1,643 @@
+//! Service lines of the config file, parsed into typed definitions.
+//!
+//! A line is `name [Directive=value ...] executable [args ...]`. Directives
+//! come before the first plain word, which is the executable unless
+//! `EXEC=path` named it explicitly; everything after is arguments. A
+//! directive value in double quotes may contain spaces:
+//! `Description="Web frontend"`.
+//!
+//! `ONSTART="cmd args"` and `ONSTOP="cmd args"` name hooks run after the
+//! service is running and after it stopped; ONSTART runs once per start
+//! unless `ONSTART_EVERY_SPAWN=yes`.
+//!
+//! `TIMEOUTSTOP=secs` shortens STOP_TIMEOUT_SEC for this service's `stop`;
+//! longer values are capped at STOP_TIMEOUT_SEC, which the client's wait is
+//! sized for, and at daemon shutdown SHUTDOWN_STOP_TIMEOUT_SEC caps it.
+//!
+//! `PRESTOP_SIGNAL=sig PRESTOP_GRACE=secs` warns the service before `stop`:
+//! the signal goes first, and SIGTERM follows once the service exited or the
+//! grace ran out, whichever comes first. The grace is part of the stop
+//! timeout, not added to it.
+//!
+//! `STOPSIGNAL=sig` replaces SIGTERM as the signal `stop` asks the service
+//! to exit with; SIGKILL at the timeout stays.
+//!
+//! `Type=oneshot` marks a service that runs to completion instead of being
+//! kept up: a clean exit is "completed", any other exit "failed", and neither
+//! is respawned. The default is `Type=simple`.
+//!
+//! `Env=KEY=VALUE` (repeatable) adds to the inherited environment, and
+//! `WorkingDir=path` is the directory the service starts in.
+//!
+//! `SECRET_ENV=A,B` names Env variables whose values are masked in every
+//! output; see `secrets`.
+//!
+//! `NETNS=name` starts the service inside the network namespace
+//! NETNS_PATH/name.
+//!
+//! `Restart=always|on-failure|never` says which exits are respawned, by
+//! default `on-failure` and `never` for a oneshot; `RestartSec=secs` is the
+//! pause before each respawn, RESTART_SEC and no pause after a run that
+//! lasted by default.
+//!
+//! After parsing, specifiers in the executable, arguments and directive values
+//! are expanded: `%N` service name, `%d` directory of the config file, `%t`
+//! the service's runtime directory under RUNTIME_PATH, `%%` a literal `%`.
+//! There are no templates, so `%i` has no instance to stand for and is an
+//! error like any unknown specifier.
+//!
+//! The runtime directory is created before each spawn and removed on stop
+//! unless `KEEP_RUNTIME_DIR=yes`.
+
+use std::fmt::{self, Display};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::config::{paths, DESCRIPTION_MAX, RESTART_SEC, STOP_TIMEOUT_SEC};
+use crate::{mounts, netns, secrets, signals};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceDefinition {
+    pub name: String,
+    pub exec: PathBuf,
+    pub args: Vec<String>,
+    /// Everything but EXEC, in file order.
+    pub directives: Vec<(String, String)>,
+}
+
+/// Directives something in the daemon reads; others are kept but do nothing.
+const KNOWN: &[&str] = &[
+    "Description",
+    "Env",
+    "Handles",
+    "KEEP_RUNTIME_DIR",
+    "NETNS",
+    "ONSTART",
+    "ONSTART_EVERY_SPAWN",
+    "ONSTOP",
+    "PIDFILE",
+    "PRESTOP_GRACE",
+    "PRESTOP_SIGNAL",
+    "Requires",
+    "Restart",
+    "RestartSec",
+    "SECRET_ENV",
+    "SOCKET",
+    "STOPSIGNAL",
+    "TIMEOUTSTOP",
+    "Type",
+    "URL",
+    "WorkingDir",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Restart {
+    Always,
+    OnFailure,
+    Never,
+}
+
+/// The command line, secrets masked.
+impl Display for ServiceDefinition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut line = self.exec.display().to_string();
+        for arg in &self.args {
+            line = format!("{} {}", line, arg);
+        }
+        write!(f, "{}", secrets::mask(&line, &self.secrets()))
+    }
+}
+
+impl ServiceDefinition {
+    /// Created before each spawn, removed on stop unless KEEP_RUNTIME_DIR=yes.
+    /// The name passed `check_name`, so this is one entry of RUNTIME_PATH.
+    pub fn runtime_dir(&self) -> PathBuf {
+        Path::new(&paths().runtime).join(&self.name)
+    }
+
+    /// Names listed in `Requires=a,b`.
+    pub fn requires(&self) -> impl Iterator<Item = &str> {
+        self.value("Requires")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|name| !name.is_empty())
+    }
+
+    /// `description <url>`, either part, or nothing.
+    pub fn about(&self) -> String {
+        match (self.value("Description"), self.value("URL")) {
+            (Some(description), Some(url)) => format!("{} <{}>", description, url),
+            (Some(description), None) => description.to_string(),
+            (None, Some(url)) => format!("<{}>", url),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// `key: value` lines for `show`, directives in file order.
+    pub fn show(&self) -> String {
+        let mut lines = vec![
+            format!("name: {}", self.name),
+            format!("exec: {}", self.exec.display()),
+            format!("args: {}", self.args.join(" ")),
+        ];
+        for (key, value) in &self.directives {
+            lines.push(format!("{}: {}", key, value));
+        }
+        lines.push(format!(
+            "stop signal: {}",
+            signals::name(self.stop_signal())
+        ));
+        secrets::mask(&lines.join("\n"), &self.secrets())
+    }
+
+    /// Optional behaviour this definition turns on, as listed in status.
+    /// Output capture is always there.
+    pub fn features(&self) -> Vec<&'static str> {
+        let mut features = vec!["log"];
+        if self.value("Handles").is_some() {
+            features.push("ext");
+        }
+        if self.keep_runtime_dir() {
+            features.push("keep-runtime");
+        }
+        if self.oneshot() {
+            features.push("oneshot");
+        }
+        if self.value("ONSTART").is_some() || self.value("ONSTOP").is_some() {
+            features.push("hooks");
+        }
+        if self.prestop().is_some() {
+            features.push("prestop");
+        }
+        if self.netns().is_some() {
+            features.push("netns");
+        }
+        features
+    }
+
+    pub fn keep_runtime_dir(&self) -> bool {
+        matches!(self.value("KEEP_RUNTIME_DIR"), Some("yes" | "true" | "1"))
+    }
+
+    /// How long `stop` waits after the stop signal before SIGKILL: `TIMEOUTSTOP=secs`
+    /// or STOP_TIMEOUT_SEC, whichever is shorter.
+    pub fn stop_timeout(&self) -> Duration {
+        let secs = self.value("TIMEOUTSTOP").and_then(|secs| secs.parse().ok());
+        Duration::from_secs(secs.unwrap_or(STOP_TIMEOUT_SEC).min(STOP_TIMEOUT_SEC))
+    }
+
+    /// `PRESTOP_SIGNAL` and `PRESTOP_GRACE`, if set.
+    pub fn prestop(&self) -> Option<(u32, Duration)> {
+        let signal = signals::parse(self.value("PRESTOP_SIGNAL")?)?;
+        let grace = self.value("PRESTOP_GRACE")?.parse().ok()?;
+        Some((signal, Duration::from_secs(grace)))
+    }
+
+    /// The signal `stop` sends before SIGKILL: `STOPSIGNAL=sig` or SIGTERM.
+    pub fn stop_signal(&self) -> u32 {
+        self.value("STOPSIGNAL")
+            .and_then(signals::parse)
+            .unwrap_or(15)
+    }
+
+    /// Directive keys given on the line that nothing reads, likely typos.
+    pub fn unknown(&self) -> Vec<&str> {
+        let mut unknown: Vec<&str> = self
+            .directives
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .filter(|key| !KNOWN.contains(key))
+            .collect();
+        unknown.sort();
+        unknown.dedup();
+        unknown
+    }
+
+    /// `Type=oneshot`: runs to completion once instead of being kept up.
+    pub fn oneshot(&self) -> bool {
+        self.value("Type") == Some("oneshot")
+    }
+
+    /// Every `Env=KEY=VALUE`, in file order.
+    pub fn env(&self) -> Vec<(String, String)> {
+        self.directives
+            .iter()
+            .filter(|(key, _)| key == "Env")
+            .filter_map(|(_, value)| value.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Names listed in `SECRET_ENV=A,B`.
+    pub fn secret_env(&self) -> Vec<&str> {
+        self.value("SECRET_ENV")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|key| !key.is_empty())
+            .collect()
+    }
+
+    /// Values of the secret `Env=` variables.
+    pub fn secrets(&self) -> Vec<String> {
+        let listed = self.secret_env();
+        self.env()
+            .into_iter()
+            .filter(|(key, _)| secrets::secret(key, &listed))
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /// Secret variables whose value shows up in the arguments.
+    pub fn leaked(&self) -> Vec<String> {
+        let listed = self.secret_env();
+        self.env()
+            .into_iter()
+            .filter(|(key, value)| {
+                secrets::secret(key, &listed)
+                    && !value.is_empty()
+                    && self.args.iter().any(|arg| arg.contains(value.as_str()))
+            })
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    pub fn netns(&self) -> Option<&str> {
+        self.value("NETNS")
+    }
+
+    pub fn working_dir(&self) -> Option<&Path> {
+        self.value("WorkingDir").map(Path::new)
+    }
+
+    /// Which exits get respawned.
+    pub fn restart(&self) -> Restart {
+        match self.value("Restart") {
+            Some("always") => Restart::Always,
+            Some("on-failure") => Restart::OnFailure,
+            Some(_) => Restart::Never,
+            None if self.oneshot() => Restart::Never,
+            None => Restart::OnFailure,
+        }
+    }
+
+    /// Pause before a respawn, the base of the crash backoff: `RestartSec=secs`
+    /// or RESTART_SEC.
+    pub fn restart_sec(&self) -> u64 {
+        let secs = self.value("RestartSec").and_then(|secs| secs.parse().ok());
+        secs.unwrap_or(RESTART_SEC)
+    }
+
+    /// Whether ONSTART also runs on respawns, not just once per start.
+    pub fn onstart_every_spawn(&self) -> bool {
+        matches!(
+            self.value("ONSTART_EVERY_SPAWN"),
+            Some("yes" | "true" | "1")
+        )
+    }
+
+    fn expand(mut self, dir: &Path) -> Result<Self, String> {
+        let runtime_dir = self.runtime_dir();
+        let expand = |value: &str| -> Result<String, String> {
+            let mut out = String::new();
+            let mut chars = value.chars();
+            while let Some(c) = chars.next() {
+                if c != '%' {
+                    out.push(c);
+                    continue;
+                }
+                match chars.next() {
+                    Some('N') => out.push_str(&self.name),
+                    Some('d') => out.push_str(&dir.display().to_string()),
+                    Some('t') => out.push_str(&runtime_dir.display().to_string()),
+                    Some('%') => out.push('%'),
+                    Some('i') => {
+                        return Err(format!(
+                            "{}: %i needs a template instance, and there are no templates",
+                            self.name
+                        ))
+                    }
+                    Some(other) => {
+                        return Err(format!("{}: unknown specifier %{}", self.name, other))
+                    }
+                    None => return Err(format!("{}: dangling % in {:?}", self.name, value)),
+                }
+            }
+            Ok(out)
+        };
+
+        let exec = PathBuf::from(expand(&self.exec.to_string_lossy())?);
+        let args = self
+            .args
+            .iter()
+            .map(|arg| expand(arg))
+            .collect::<Result<_, _>>()?;
+        let directives = self
+            .directives
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), expand(value)?)))
+            .collect::<Result<_, String>>()?;
+
+        (self.exec, self.args, self.directives) = (exec, args, directives);
+        Ok(self)
+    }
+
+    /// The last value given for directive `key`.
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.directives
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Mtime and size of the executable as exec would find it now.
+    pub fn stamp(&self) -> Option<(SystemTime, u64)> {
+        let metadata = std::fs::metadata(mounts::resolve(&self.exec)?).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+}
+
+/// `Key=value` with a capitalized key; anything else is a plain word.
+fn directive(word: &str) -> Option<(&str, &str)> {
+    let (key, value) = word.split_once('=')?;
+    match key.starts_with(|c: char| c.is_ascii_uppercase())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        true => Some((key, value)),
+        false => None,
+    }
+}
+
+/// Whether `name` can name a service. It becomes a path component under
+/// RUNTIME_PATH and SERVICE_LOG_PATH, so nothing that leaves or is not a
+/// single directory entry passes, nor anything the client would take for
+/// a flag.
+pub fn check_name(name: &str) -> Result<(), String> {
+    let bad = name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.starts_with('-')
+        || name.contains(['/', '\0'])
+        || name.contains(char::is_whitespace);
+    match bad {
+        true => Err(format!("bad service name {:?}", name)),
+        false => Ok(()),
+    }
+}
+
+/// One config line; `Ok(None)` for blank lines.
+pub fn parse(line: &str) -> Result<Option<ServiceDefinition>, String> {
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name.to_string(),
+        None => return Ok(None),
+    };
+    check_name(&name)?;
+
+    let mut exec = None;
+    let mut directives = Vec::new();
+    let mut args = Vec::new();
+    while let Some(word) = words.next() {
+        // a quoted value runs on to the word closing the quote
+        let joined;
+        let word = match word.split_once("=\"") {
+            Some((key, rest)) if directive(word).is_some() => {
+                let mut value = rest.to_string();
+                while !value.ends_with('"') {
+                    match words.next() {
+                        Some(more) => value = format!("{} {}", value, more),
+                        None => return Err(format!("{}: unterminated quote in {}", name, key)),
+                    }
+                }
+                value.pop();
+                joined = format!("{}={}", key, value);
+                joined.as_str()
+            }
+            _ => word,
+        };
+
+        match directive(word) {
+            Some(("EXEC", "")) => return Err(format!("{}: EXEC is empty", name)),
+            Some(("EXEC", value)) => exec = Some(PathBuf::from(value)),
+            Some(("Handles", value)) if value.len() < 3 || !value.starts_with("x-") => {
+                return Err(format!(
+                    "{}: Handles needs an x- prefix, not {:?}",
+                    name, value
+                ))
+            }
+            Some(("TIMEOUTSTOP", value)) if value.parse::<u64>().is_err() => {
+                return Err(format!(
+                    "{}: TIMEOUTSTOP needs seconds, not {:?}",
+                    name, value
+                ))
+            }
+            Some(("PRESTOP_SIGNAL", value)) if signals::parse(value).is_none() => {
+                return Err(format!("{}: unknown PRESTOP_SIGNAL {:?}", name, value))
+            }
+            Some(("STOPSIGNAL", value)) if signals::parse(value).is_none() => {
+                return Err(format!("{}: unknown STOPSIGNAL {:?}", name, value))
+            }
+            Some(("PRESTOP_GRACE", value)) if value.parse::<u64>().is_err() => {
+                return Err(format!(
+                    "{}: PRESTOP_GRACE needs seconds, not {:?}",
+                    name, value
+                ))
+            }
+            Some(("Type", value)) if !matches!(value, "simple" | "oneshot") => {
+                return Err(format!("{}: unknown Type {:?}", name, value))
+            }
+            Some(("Env", value)) if value.split_once('=').is_none_or(|(key, _)| key.is_empty()) => {
+                // no echo: a malformed Env may well be a pasted secret
+                return Err(format!("{}: Env needs KEY=VALUE", name));
+            }
+            Some(("NETNS", value)) if value.is_empty() || value.contains('/') => {
+                return Err(format!(
+                    "{}: NETNS needs a namespace name, not {:?}",
+                    name, value
+                ))
+            }
+            Some(("NETNS", _)) if !netns::supported() => {
+                return Err(format!(
+                    "{}: NETNS needs network namespaces, which this kernel lacks",
+                    name
+                ))
+            }
+            Some(("WorkingDir", "")) => return Err(format!("{}: WorkingDir is empty", name)),
+            Some(("Restart", value)) if !matches!(value, "always" | "on-failure" | "never") => {
+                return Err(format!("{}: unknown Restart {:?}", name, value))
+            }
+            Some(("RestartSec", value)) if value.parse::<u64>().is_err() => {
+                return Err(format!(
+                    "{}: RestartSec needs seconds, not {:?}",
+                    name, value
+                ))
+            }
+            Some(("Description", value)) => directives.push((
+                String::from("Description"),
+                match value.chars().count() > DESCRIPTION_MAX {
+                    true => value.chars().take(DESCRIPTION_MAX).chain(['…']).collect(),
+                    false => value.to_string(),
+                },
+            )),
+            Some((key, value)) => directives.push((key.to_string(), value.to_string())),
+            None => {
+                match exec {
+                    Some(_) => args.push(word.to_string()),
+                    None => exec = Some(PathBuf::from(word)),
+                }
+                break;
+            }
+        }
+    }
+    args.extend(words.map(|arg| arg.to_string()));
+
+    let last = |key: &str| {
+        directives
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    };
+    if last("PRESTOP_SIGNAL").is_some() != last("PRESTOP_GRACE").is_some() {
+        return Err(format!(
+            "{}: PRESTOP_SIGNAL and PRESTOP_GRACE go together",
+            name
+        ));
+    }
+    if last("Type") == Some("oneshot") && last("Restart") == Some("always") {
+        return Err(format!("{}: Restart=always contradicts Type=oneshot", name));
+    }
+
+    match exec {
+        Some(exec) => Ok(Some(ServiceDefinition {
+            name,
+            exec,
+            args,
+            directives,
+        })),
+        None => Err(format!("{}: no executable", name)),
+    }
+}
+
+/// Every service of the config file at `fpath`, or the first problem with it.
+/// A file without any service is one: more likely cut short than meant.
+pub fn load(fpath: &str) -> Result<Vec<ServiceDefinition>, String> {
+    let file =
+        std::fs::File::open(fpath).map_err(|e| format!("config: bad open {}: {}", fpath, e))?;
+
+    let dir = std::fs::canonicalize(fpath)
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .unwrap_or_default();
+
+    let mut definitions = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("config: bad read {}: {}", fpath, e))?;
+        match parse(&line).and_then(|definition| definition.map(|d| d.expand(&dir)).transpose()) {
+            Ok(Some(definition)) => definitions.push(definition),
+            Ok(None) => (),
+            Err(e) => return Err(format!("config: {}:{}: {}", fpath, number + 1, e)),
+        }
+    }
+    match definitions.is_empty() {
+        true => Err(format!("config: {}: no services in it", fpath)),
+        false => Ok(definitions),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_that_escape_are_refused() {
+        for name in [
+            "", ".", "..", "a/b", "../etc", "/abs", "-f", "--all", "a\0b", "a b",
+        ] {
+            assert!(check_name(name).is_err(), "{:?}", name);
+        }
+        for name in ["web", "web.1", "a-b", "a..b", "x_y", "..hidden"] {
+            assert_eq!(check_name(name), Ok(()), "{:?}", name);
+        }
+    }
+
+    /// A config file holding `contents`; its path.
+    fn config(test: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("dctl-load-{}-{}", test, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn empty_config_is_an_error() {
+        crate::config::test_paths();
+        for (test, contents) in [("empty", ""), ("blank", "\n  \n\t\n")] {
+            let path = config(test, contents);
+            assert_eq!(
+                load(&path),
+                Err(format!("config: {}: no services in it", path))
+            );
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+
+    #[test]
+    fn line_without_executable_names_file_and_line() {
+        crate::config::test_paths();
+        let path = config("no-exec", "web /bin/true\ndb Restart=always\n");
+        assert_eq!(
+            load(&path),
+            Err(format!("config: {}:2: db: no executable", path))
+        );
+        let _ = std::fs::remove_file(&path);
+
+        let path = config("exec", "db Restart=always EXEC=/bin/true -v\n");
+        let loaded = load(&path).unwrap();
+        assert_eq!(loaded[0].exec, PathBuf::from("/bin/true"));
+        assert_eq!(loaded[0].args, ["-v"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn stop_timeout_stays_within_the_client_wait() {
+        let timeout = |line| parse(line).unwrap().unwrap().stop_timeout();
+        assert_eq!(
+            timeout("web /bin/true"),
+            Duration::from_secs(STOP_TIMEOUT_SEC)
+        );
+        assert_eq!(
+            timeout("web TIMEOUTSTOP=3 /bin/true"),
+            Duration::from_secs(3)
+        );
+        assert_eq!(
+            timeout("web TIMEOUTSTOP=600 /bin/true"),
+            Duration::from_secs(STOP_TIMEOUT_SEC)
+        );
+    }
+
+    #[test]
+    fn instance_specifier_is_refused() {
+        crate::config::test_paths();
+        let path = config("instance", "web /bin/echo %i\n");
+        assert_eq!(
+            load(&path),
+            Err(format!(
+                "config: {}:1: web: %i needs a template instance, and there are no templates",
+                path
+            ))
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn parse_refuses_bad_names() {
+        assert_eq!(
+            parse(".. /bin/true"),
+            Err(String::from("bad service name \"..\""))
+        );
+        assert!(parse("-x /bin/true").is_err());
+        assert!(parse("a/b /bin/true").is_err());
+        assert!(parse("web /bin/true").unwrap().is_some());
+    }
+}
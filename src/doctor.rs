@@ -0,0 +1,408 @@
+//! Consistency checks between what the daemon believes and what the kernel
+//! and filesystem say, behind `daemon#doctor`.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use crate::config::FD_WARN_PERCENT;
+use crate::fds;
+
+enum Severity {
+    Warn,
+    Error,
+}
+
+pub struct Finding {
+    severity: Severity,
+    message: String,
+}
+
+impl Finding {
+    fn warn(message: String) -> Self {
+        Self {
+            severity: Severity::Warn,
+            message,
+        }
+    }
+
+    fn error(message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            message,
+        }
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warn => "warn",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}", severity, self.message)
+    }
+}
+
+/// The view of processes the checks rely on, so they can run on fake data.
+pub trait Proc {
+    fn exists(&self, pid: u32) -> bool;
+    fn cmdline(&self, pid: u32) -> Option<Vec<String>>;
+    /// The state letter of /proc/<pid>/stat, `Z` for a zombie.
+    fn state(&self, pid: u32) -> Option<char>;
+    /// Open fds and the soft limit on them.
+    fn fds(&self, pid: u32) -> Option<(usize, u64)>;
+}
+
+pub struct RealProc;
+
+impl Proc for RealProc {
+    fn exists(&self, pid: u32) -> bool {
+        Path::new(&format!("/proc/{}", pid)).exists()
+    }
+
+    fn cmdline(&self, pid: u32) -> Option<Vec<String>> {
+        let raw = std::fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+        Some(
+            raw.split(|byte| *byte == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect(),
+        )
+    }
+
+    fn state(&self, pid: u32) -> Option<char> {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        // the command in parentheses may hold spaces and parentheses itself
+        let (_, rest) = stat.rsplit_once(')')?;
+        rest.trim_start().chars().next()
+    }
+
+    fn fds(&self, pid: u32) -> Option<(usize, u64)> {
+        Some((fds::count(pid)?, fds::limit(pid)?))
+    }
+}
+
+/// A service the daemon believes to be running.
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub command: &'a Path,
+    pub pid: u32,
+}
+
+pub fn missing_pids(proc: &dyn Proc, entries: &[Entry]) -> Vec<Finding> {
+    entries
+        .iter()
+        .filter(|entry| !proc.exists(entry.pid))
+        .map(|entry| Finding::error(format!("{}: pid {} does not exist", entry.name, entry.pid)))
+        .collect()
+}
+
+/// A zombie is a child the daemon has not reaped. Only a warning: one that
+/// just exited stays a zombie until its supervisor's next tick.
+pub fn zombies(proc: &dyn Proc, entries: &[Entry]) -> Vec<Finding> {
+    entries
+        .iter()
+        .filter(|entry| proc.state(entry.pid) == Some('Z'))
+        .map(|entry| {
+            Finding::warn(format!(
+                "{}: pid {} is a zombie, not reaped",
+                entry.name, entry.pid
+            ))
+        })
+        .collect()
+}
+
+/// Services at FD_WARN_PERCENT of their fd limit warn; out of fds is an error.
+pub fn fd_exhaustion(proc: &dyn Proc, entries: &[Entry]) -> Vec<Finding> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let (open, limit) = proc.fds(entry.pid)?;
+            let message = format!(
+                "{}: pid {} has {}/{} fds open",
+                entry.name, entry.pid, open, limit
+            );
+            match open as u64 {
+                open if limit > 0 && open >= limit => Some(Finding::error(message)),
+                open if limit > 0 && open * 100 / limit >= FD_WARN_PERCENT => {
+                    Some(Finding::warn(message))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// The executable shows up as argv[0], or argv[1] when run by an interpreter.
+pub fn cmdline_mismatches(proc: &dyn Proc, entries: &[Entry]) -> Vec<Finding> {
+    let base = |path: &Path| path.file_name().map(|name| name.to_owned());
+
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let cmdline = proc.cmdline(entry.pid)?;
+            let matches = cmdline
+                .iter()
+                .take(2)
+                .any(|arg| base(Path::new(arg)) == base(entry.command));
+            match matches {
+                true => None,
+                false => Some(Finding::warn(format!(
+                    "{}: pid {} runs '{}', not {}",
+                    entry.name,
+                    entry.pid,
+                    cmdline.join(" "),
+                    entry.command.display()
+                ))),
+            }
+        })
+        .collect()
+}
+
+pub fn duplicate_pids(entries: &[Entry]) -> Vec<Finding> {
+    let mut owners: HashMap<u32, Vec<&str>> = HashMap::new();
+    for entry in entries {
+        owners.entry(entry.pid).or_default().push(entry.name);
+    }
+
+    let mut findings: Vec<Finding> = owners
+        .into_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .map(|(pid, mut names)| {
+            names.sort();
+            Finding::error(format!("pid {} claimed by {}", pid, names.join(", ")))
+        })
+        .collect();
+    findings.sort_by(|a, b| a.message.cmp(&b.message));
+    findings
+}
+
+/// What a service holds that another one must not: the argv it runs (only
+/// while running) and its declared `PIDFILE=` and `SOCKET=`.
+pub struct Claims<'a> {
+    pub name: &'a str,
+    pub argv: Option<&'a str>,
+    pub pidfile: Option<&'a str>,
+    pub socket: Option<&'a str>,
+}
+
+type Resource = fn(&Claims<'_>) -> Option<String>;
+
+/// Services holding the same resource, one finding per resource. Purely
+/// informational: nothing is refused because of it.
+pub fn collisions(claims: &[Claims]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let kinds: [(&str, Resource); 3] = [
+        ("run the same", |claims| {
+            claims.argv.map(|argv| format!("'{}'", argv))
+        }),
+        ("share pidfile", |claims| claims.pidfile.map(String::from)),
+        ("claim socket", |claims| claims.socket.map(String::from)),
+    ];
+    for (verb, resource) in kinds {
+        let mut holders: HashMap<String, Vec<&str>> = HashMap::new();
+        for claim in claims {
+            if let Some(resource) = resource(claim) {
+                holders.entry(resource).or_default().push(claim.name);
+            }
+        }
+        let mut found: Vec<Finding> = holders
+            .into_iter()
+            .filter(|(_, names)| names.len() > 1)
+            .map(|(resource, mut names)| {
+                names.sort();
+                Finding::warn(format!("{} {} {}", names.join(", "), verb, resource))
+            })
+            .collect();
+        found.sort_by(|a, b| a.message.cmp(&b.message));
+        findings.extend(found);
+    }
+    findings
+}
+
+pub fn socket(path: &str) -> Vec<Finding> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => Vec::new(),
+        Ok(_) => vec![Finding::error(format!("socket: {} is not a socket", path))],
+        Err(e) => vec![Finding::error(format!("socket: {}: {}", path, e))],
+    }
+}
+
+pub fn writable(label: &str, path: &str) -> Vec<Finding> {
+    match std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+    {
+        Ok(_) => Vec::new(),
+        Err(e) => vec![Finding::error(format!(
+            "{}: {} not writable: {}",
+            label, path, e
+        ))],
+    }
+}
+
+pub fn readable(label: &str, path: &str) -> Vec<Finding> {
+    match std::fs::File::open(path) {
+        Ok(_) => Vec::new(),
+        Err(e) => vec![Finding::error(format!(
+            "{}: {} not readable: {}",
+            label, path, e
+        ))],
+    }
+}
+
+/// What the startup repair pass saw but would not fix on its own.
+pub fn untouched(messages: Vec<String>) -> Vec<Finding> {
+    messages
+        .into_iter()
+        .map(|message| Finding::warn(format!("{} (not repaired)", message)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What /proc would say about one process.
+    struct Process {
+        cmdline: &'static [&'static str],
+        state: char,
+        fds: (usize, u64),
+    }
+
+    struct FakeProc(HashMap<u32, Process>);
+
+    impl Proc for FakeProc {
+        fn exists(&self, pid: u32) -> bool {
+            self.0.contains_key(&pid)
+        }
+
+        fn cmdline(&self, pid: u32) -> Option<Vec<String>> {
+            let process = self.0.get(&pid)?;
+            Some(process.cmdline.iter().map(|arg| arg.to_string()).collect())
+        }
+
+        fn state(&self, pid: u32) -> Option<char> {
+            Some(self.0.get(&pid)?.state)
+        }
+
+        fn fds(&self, pid: u32) -> Option<(usize, u64)> {
+            Some(self.0.get(&pid)?.fds)
+        }
+    }
+
+    fn healthy(cmdline: &'static [&'static str]) -> Process {
+        Process {
+            cmdline,
+            state: 'S',
+            fds: (4, 1024),
+        }
+    }
+
+    fn entry(name: &'static str, command: &'static str, pid: u32) -> Entry<'static> {
+        Entry {
+            name,
+            command: Path::new(command),
+            pid,
+        }
+    }
+
+    fn messages(findings: Vec<Finding>) -> Vec<String> {
+        findings.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn missing_pid_is_an_error() {
+        let proc = FakeProc(HashMap::from([(10, healthy(&["/bin/web"]))]));
+        let entries = [entry("web", "/bin/web", 10), entry("db", "/bin/db", 11)];
+        assert_eq!(
+            messages(missing_pids(&proc, &entries)),
+            ["error: db: pid 11 does not exist"]
+        );
+    }
+
+    #[test]
+    fn zombie_is_found() {
+        let mut zombie = healthy(&[]);
+        zombie.state = 'Z';
+        let proc = FakeProc(HashMap::from([(10, healthy(&["/bin/web"])), (11, zombie)]));
+        let entries = [entry("web", "/bin/web", 10), entry("db", "/bin/db", 11)];
+        assert_eq!(
+            messages(zombies(&proc, &entries)),
+            ["warn: db: pid 11 is a zombie, not reaped"]
+        );
+        // a vanished pid is missing_pids' finding, not this one's
+        assert!(zombies(&proc, &[entry("gone", "/bin/gone", 12)]).is_empty());
+    }
+
+    #[test]
+    fn fd_exhaustion_warns_then_errors() {
+        let at = |open, limit| Process {
+            fds: (open, limit),
+            ..healthy(&["/bin/x"])
+        };
+        let proc = FakeProc(HashMap::from([
+            (10, at(10, 1024)),
+            (11, at(820, 1024)),
+            (12, at(1024, 1024)),
+            (13, at(5, 0)),
+        ]));
+        let entries = [
+            entry("fine", "/bin/x", 10),
+            entry("near", "/bin/x", 11),
+            entry("out", "/bin/x", 12),
+            entry("unlimited", "/bin/x", 13),
+            entry("gone", "/bin/x", 14),
+        ];
+        assert_eq!(
+            messages(fd_exhaustion(&proc, &entries)),
+            [
+                "warn: near: pid 11 has 820/1024 fds open",
+                "error: out: pid 12 has 1024/1024 fds open"
+            ]
+        );
+    }
+
+    #[test]
+    fn cmdline_matches_the_executable_or_its_interpreter() {
+        let proc = FakeProc(HashMap::from([
+            (10, healthy(&["/usr/bin/web", "--port=80"])),
+            (11, healthy(&["/bin/sh", "/etc/dctl/job.sh"])),
+            (12, healthy(&["/bin/other"])),
+        ]));
+        let entries = [
+            entry("web", "/bin/web", 10),
+            entry("job", "/etc/dctl/job.sh", 11),
+            entry("db", "/bin/db", 12),
+        ];
+        assert_eq!(
+            messages(cmdline_mismatches(&proc, &entries)),
+            ["warn: db: pid 12 runs '/bin/other', not /bin/db"]
+        );
+    }
+
+    #[test]
+    fn duplicate_pids_name_every_claimant() {
+        let entries = [
+            entry("b", "/bin/x", 10),
+            entry("a", "/bin/x", 10),
+            entry("c", "/bin/x", 11),
+        ];
+        assert_eq!(
+            messages(duplicate_pids(&entries)),
+            ["error: pid 10 claimed by a, b"]
+        );
+    }
+
+    #[test]
+    fn real_proc_sees_this_process() {
+        let pid = std::process::id();
+        assert!(RealProc.exists(pid));
+        assert!(matches!(RealProc.state(pid), Some('R' | 'S')));
+        let (open, limit) = RealProc.fds(pid).unwrap();
+        assert!(open >= 3 && limit > 0, "{}/{}", open, limit);
+    }
+}
@@ -0,0 +1,61 @@
+//! Bounded history of notable daemon events, served by `daemon#events`.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{EVENTS_MAX, STORED_MAX};
+use crate::memory;
+
+static EVENTS: Mutex<VecDeque<(u64, String)>> = Mutex::new(VecDeque::new());
+
+pub fn record(event: String) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+
+    let mut events = EVENTS.lock().unwrap();
+    if events.len() == EVENTS_MAX {
+        events.pop_front();
+    }
+    events.push_back((now, memory::cap(event, STORED_MAX)));
+}
+
+/// Approximate bytes held by the history.
+pub fn bytes() -> usize {
+    let events = EVENTS.lock().unwrap();
+    events.capacity() * std::mem::size_of::<(u64, String)>()
+        + events
+            .iter()
+            .map(|(_, event)| event.capacity())
+            .sum::<usize>()
+}
+
+/// The last `count` events naming `name` as a word, oldest first.
+pub fn about(name: &str, count: usize) -> Vec<String> {
+    let events = EVENTS.lock().unwrap();
+    let mut about: Vec<String> = events
+        .iter()
+        .rev()
+        .filter(|(_, event)| {
+            event
+                .split(|c: char| c.is_whitespace() || c == ':' || c == ',')
+                .any(|word| word == name)
+        })
+        .take(count)
+        .map(|(at, event)| format!("{} {}", at, event))
+        .collect();
+    about.reverse();
+    about
+}
+
+/// `timestamp event` lines, oldest first.
+pub fn render() -> String {
+    EVENTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(at, event)| format!("{} {}", at, event))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
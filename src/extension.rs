@@ -0,0 +1,50 @@
+//! `x-` verbs forwarded to the service that declared `Handles=x-<prefix>`.
+//!
+//! The handler listens on the unix socket named by EXT_SOCKET_ENV, gets the
+//! request exactly as the client sent it, and answers until EOF; the daemon
+//! adds END_MARKER on the way back.
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::config::{paths, END_MARKER, EXT_TIMEOUT_SEC};
+
+/// Where the handler service `name` is expected to listen.
+pub fn socket(name: &str) -> String {
+    format!("{}.{}", &paths().socket, name)
+}
+
+/// Whether `verb` falls under the declared `prefix`: the prefix itself or
+/// the prefix followed by `-`.
+pub fn matches(prefix: &str, verb: &str) -> bool {
+    match verb.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('-'),
+        None => false,
+    }
+}
+
+pub fn forward(name: &str, message: &str) -> String {
+    let path = socket(name);
+    let timeout = Some(Duration::from_secs(EXT_TIMEOUT_SEC));
+
+    let exchange = || -> std::io::Result<String> {
+        let mut stream = UnixStream::connect(&path)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        stream.write_all(message.as_bytes())?;
+        stream.shutdown(std::net::Shutdown::Write)?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    };
+
+    match exchange() {
+        Ok(response) => match response.strip_suffix(END_MARKER) {
+            Some(response) => response.to_string(),
+            None => response,
+        },
+        Err(e) => format!("extension: {} on {}: {}", name, path, e),
+    }
+}
@@ -0,0 +1,152 @@
+//! Why a service failed: a stable code for scripts and alerting next to the
+//! reason meant for people.
+//!
+//! The codes are part of the interface: they show up in status, events and
+//! `wait` answers, and a code once shipped keeps its meaning and spelling.
+
+use std::fmt::{self, Display};
+use std::io::ErrorKind;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Code {
+    /// The executable or its interpreter does not exist.
+    ExecNotFound,
+    /// The executable exists but may not be run.
+    PermissionDenied,
+    /// Crashed START_LIMIT times in a row.
+    StartLimit,
+    /// A oneshot was killed by a signal.
+    Signal,
+    /// A oneshot exited with a non-zero code.
+    ExitStatus,
+    /// The `NETNS=` namespace is missing or may not be entered.
+    NetnsUnavailable,
+    /// The daemon itself could not run the service.
+    SupervisorError,
+}
+
+impl Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = match self {
+            Code::ExecNotFound => "exec-not-found",
+            Code::PermissionDenied => "permission-denied",
+            Code::StartLimit => "start-limit",
+            Code::Signal => "signal",
+            Code::ExitStatus => "exit-status",
+            Code::NetnsUnavailable => "netns-unavailable",
+            Code::SupervisorError => "supervisor-error",
+        };
+        write!(f, "{}", code)
+    }
+}
+
+impl Code {
+    /// The code for a spawn that failed with `e`.
+    pub fn spawn(e: &std::io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::NotFound => Code::ExecNotFound,
+            ErrorKind::PermissionDenied => Code::PermissionDenied,
+            _ => Code::SupervisorError,
+        }
+    }
+
+    /// The code for a oneshot that ended with `exit`.
+    pub fn exit(exit: ExitStatus) -> Self {
+        match exit.signal() {
+            Some(_) => Code::Signal,
+            None => Code::ExitStatus,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Failure {
+    code: Code,
+    reason: String,
+}
+
+impl Failure {
+    pub fn new(code: Code, reason: String) -> Self {
+        Self { code, reason }
+    }
+
+    pub fn code(&self) -> Code {
+        self.code
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+/// `reason [code]`.
+impl Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [{}]", self.reason, self.code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Error;
+
+    #[test]
+    fn spawn_errors_map_to_codes() {
+        assert_eq!(
+            Code::spawn(&Error::from(ErrorKind::NotFound)),
+            Code::ExecNotFound
+        );
+        assert_eq!(
+            Code::spawn(&Error::from(ErrorKind::PermissionDenied)),
+            Code::PermissionDenied
+        );
+        assert_eq!(
+            Code::spawn(&Error::from(ErrorKind::OutOfMemory)),
+            Code::SupervisorError
+        );
+    }
+
+    #[test]
+    fn exits_map_to_codes() {
+        // raw wait statuses: exit code in the high byte, signal in the low
+        assert_eq!(Code::exit(ExitStatus::from_raw(3 << 8)), Code::ExitStatus);
+        assert_eq!(Code::exit(ExitStatus::from_raw(9)), Code::Signal);
+    }
+
+    #[test]
+    fn codes_keep_their_spelling() {
+        let codes = [
+            Code::ExecNotFound,
+            Code::PermissionDenied,
+            Code::StartLimit,
+            Code::Signal,
+            Code::ExitStatus,
+            Code::NetnsUnavailable,
+            Code::SupervisorError,
+        ];
+        let spelled: Vec<String> = codes.iter().map(Code::to_string).collect();
+        assert_eq!(
+            spelled,
+            [
+                "exec-not-found",
+                "permission-denied",
+                "start-limit",
+                "signal",
+                "exit-status",
+                "netns-unavailable",
+                "supervisor-error"
+            ]
+        );
+        let failure = Failure::new(
+            Code::StartLimit,
+            String::from("exit status: 1, start limit hit"),
+        );
+        assert_eq!(
+            failure.to_string(),
+            "exit status: 1, start limit hit [start-limit]"
+        );
+    }
+}
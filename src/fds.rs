@@ -0,0 +1,37 @@
+//! Open file descriptors of service processes, read from /proc on demand
+//! only: for `status --full` and `daemon#fdtop`, never on the hot path.
+
+use crate::config::FD_WARN_PERCENT;
+
+/// Entries in /proc/<pid>/fd, None if unreadable.
+pub fn count(pid: u32) -> Option<usize> {
+    Some(std::fs::read_dir(format!("/proc/{}/fd", pid)).ok()?.count())
+}
+
+/// Soft RLIMIT_NOFILE from /proc/<pid>/limits, None if unreadable or unlimited.
+pub fn limit(pid: u32) -> Option<u64> {
+    let limits = std::fs::read_to_string(format!("/proc/{}/limits", pid)).ok()?;
+    let line = limits
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?;
+    line.trim_start_matches("Max open files")
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Count against limit, and whether it is at FD_WARN_PERCENT or above.
+pub fn usage(pid: u32) -> (String, bool) {
+    match (count(pid), limit(pid)) {
+        (Some(count), Some(limit)) if limit > 0 => {
+            let percent = count as u64 * 100 / limit;
+            (
+                format!("{}/{} ({}%)", count, limit, percent),
+                percent >= FD_WARN_PERCENT,
+            )
+        }
+        (Some(count), _) => (count.to_string(), false),
+        (None, _) => (String::from("unknown"), false),
+    }
+}
@@ -0,0 +1,78 @@
+//! `ONSTART="cmd args"` and `ONSTOP="cmd args"`: commands run beside a
+//! service once it is running and once it has stopped for good.
+//!
+//! Unlike the service itself a hook is fire and forget: it runs detached with
+//! the service's environment plus HOOK_ENV, is killed after HOOK_TIMEOUT_SEC,
+//! and only its outcome is logged. A failing hook never changes the state of
+//! the service.
+
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::config::{HOOK_ENV, HOOK_TIMEOUT_SEC, SUPERVISE_TICK_MS};
+use crate::libc::{inherited_fds, reset_child};
+
+/// Runs `command` of hook `which` for service `name` on its own thread;
+/// the log shows it as `shown`, with secrets masked.
+pub fn fire(
+    name: &str,
+    which: &'static str,
+    command: &str,
+    shown: &str,
+    env: Vec<(String, String)>,
+) {
+    let mut words = command.split_whitespace().map(String::from);
+    let Some(exec) = words.next() else {
+        return;
+    };
+    let (name, args): (String, Vec<String>) = (name.to_string(), words.collect());
+    let shown = shown.to_string();
+
+    thread::spawn(move || {
+        let inherited = inherited_fds();
+        let mut command = Command::new(&exec);
+        command
+            .args(&args)
+            .envs(env)
+            .env(HOOK_ENV, "1")
+            .stdin(Stdio::null());
+        let spawned = unsafe {
+            command.pre_exec(move || {
+                reset_child(&inherited);
+                Ok(())
+            })
+        }
+        .spawn();
+
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("hook: {} {}: bad start {}: {}", name, which, shown, e);
+                return;
+            }
+        };
+        info!("hook: {} {}: {}", name, which, shown);
+
+        let deadline = Instant::now() + Duration::from_secs(HOOK_TIMEOUT_SEC);
+        loop {
+            match child.try_wait() {
+                Ok(Some(exit)) if exit.success() => return,
+                Ok(Some(exit)) => return warn!("hook: {} {}: {}", name, which, exit),
+                Ok(None) if Instant::now() >= deadline => break,
+                Ok(None) => thread::sleep(Duration::from_millis(SUPERVISE_TICK_MS)),
+                Err(e) => return warn!("hook: {} {}: bad wait: {}", name, which, e),
+            }
+        }
+
+        warn!(
+            "hook: {} {}: killed after {}s",
+            name, which, HOOK_TIMEOUT_SEC
+        );
+        let _ = child.kill();
+        let _ = child.wait();
+    });
+}
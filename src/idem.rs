@@ -0,0 +1,175 @@
+//! Replay of responses to mutating requests that carry an idempotency key.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::config::{IDEM_MAX, IDEM_TTL_SEC};
+use crate::stats;
+
+#[derive(Default)]
+struct Flight {
+    response: Mutex<Option<String>>,
+    done: Condvar,
+}
+
+enum Entry {
+    InFlight(Arc<Flight>),
+    Done(String, Instant),
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<String, Entry>,
+    /// Completed keys, oldest first.
+    order: VecDeque<String>,
+}
+
+impl Cache {
+    fn expire(&mut self) {
+        let ttl = Duration::from_secs(IDEM_TTL_SEC);
+        while let Some(key) = self.order.front() {
+            let expired = match self.entries.get(key) {
+                Some(Entry::Done(_, at)) => at.elapsed() > ttl || self.order.len() > IDEM_MAX,
+                _ => true,
+            };
+            if !expired {
+                break;
+            }
+            if let Some(key) = self.order.pop_front() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(Mutex::default);
+
+/// What duplicates waiting on a flight get if its `execute` panicked.
+const ABANDONED: &str = "idem: the original request failed, retry";
+
+/// Lands an abandoned flight if `execute` unwinds: the key is forgotten, so a
+/// retry runs afresh, and the duplicates waiting on it get ABANDONED.
+struct Unwind<'a> {
+    key: &'a str,
+    flight: &'a Flight,
+}
+
+impl Drop for Unwind<'_> {
+    fn drop(&mut self) {
+        let mut cache = CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+        cache.entries.remove(self.key);
+        drop(cache);
+
+        let mut response = self
+            .flight
+            .response
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        response.get_or_insert_with(|| String::from(ABANDONED));
+        self.flight.done.notify_all();
+    }
+}
+
+/// Approximate bytes held by the cache: keys twice, stored responses once.
+pub fn bytes() -> usize {
+    let cache = CACHE.lock().unwrap();
+    let responses: usize = cache
+        .entries
+        .values()
+        .map(|entry| match entry {
+            Entry::Done(response, _) => response.capacity(),
+            Entry::InFlight(_) => 0,
+        })
+        .sum();
+    cache.order.iter().map(String::capacity).sum::<usize>()
+        + cache.entries.keys().map(String::capacity).sum::<usize>()
+        + responses
+}
+
+/// Runs `execute` at most once per key: repeats get the stored response, and
+/// duplicates arriving while the first is still running wait for its result.
+pub fn once(key: Option<&str>, execute: impl FnOnce() -> String) -> String {
+    let key = match key {
+        Some(key) => key,
+        None => return execute(),
+    };
+
+    let mut cache = CACHE.lock().unwrap();
+    cache.expire();
+
+    let flight = match cache.entries.get(key) {
+        Some(Entry::Done(response, _)) => {
+            stats::IDEM_HITS.fetch_add(1, Ordering::Relaxed);
+            return response.clone();
+        }
+        Some(Entry::InFlight(flight)) => {
+            let flight = Arc::clone(flight);
+            drop(cache);
+            stats::IDEM_HITS.fetch_add(1, Ordering::Relaxed);
+
+            let mut response = flight.response.lock().unwrap();
+            while response.is_none() {
+                response = flight.done.wait(response).unwrap();
+            }
+            return response.clone().unwrap_or_default();
+        }
+        None => {
+            let flight = Arc::new(Flight::default());
+            cache
+                .entries
+                .insert(key.to_string(), Entry::InFlight(Arc::clone(&flight)));
+            flight
+        }
+    };
+    drop(cache);
+
+    let unwind = Unwind {
+        key,
+        flight: &flight,
+    };
+    let response = execute();
+    std::mem::forget(unwind);
+
+    *flight.response.lock().unwrap() = Some(response.clone());
+    flight.done.notify_all();
+
+    let mut cache = CACHE.lock().unwrap();
+    cache.entries.insert(
+        key.to_string(),
+        Entry::Done(response.clone(), Instant::now()),
+    );
+    cache.order.push_back(key.to_string());
+    cache.expire();
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn a_panicking_execute_releases_its_key() {
+        let key = "idem-test-panic";
+        let (started, running) = mpsc::channel();
+        let first = thread::spawn(move || {
+            once(Some(key), || {
+                started.send(()).unwrap();
+                thread::sleep(Duration::from_millis(300));
+                panic!("execute failed");
+            })
+        });
+        running.recv().unwrap();
+
+        let duplicate = thread::spawn(move || once(Some(key), || String::from("duplicate ran")));
+        assert!(first.join().is_err());
+        assert_eq!(duplicate.join().unwrap(), ABANDONED);
+
+        assert_eq!(once(Some(key), || String::from("retried")), "retried");
+        assert_eq!(once(Some(key), || String::from("again")), "retried");
+    }
+}
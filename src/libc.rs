@@ -1,7 +1,145 @@
 extern "C" {
     fn kill(pid: u32, sig: u32) -> i32;
+    // long is isize, nfds_t usize: their width follows the target
+    fn syscall(number: isize, ...) -> isize;
+    fn sigprocmask(how: i32, set: *const u64, oldset: *mut u64) -> i32;
+    fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+    fn getsockopt(fd: i32, level: i32, name: i32, value: *mut Ucred, len: *mut u32) -> i32;
+    fn poll(fds: *mut PollFd, nfds: usize, timeout: i32) -> i32;
+    fn geteuid() -> u32;
+    fn setns(fd: i32, nstype: i32) -> i32;
 }
 
+#[repr(C)]
+struct PollFd {
+    fd: i32,
+    events: i16,
+    revents: i16,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Ucred {
+    pid: i32,
+    uid: u32,
+    gid: u32,
+}
+
+/// Longest path a unix socket address holds, sun_path less its NUL.
+pub const SUN_PATH_MAX: usize = 107;
+
+/// rt_sigaction(2), called directly: glibc's wrappers refuse the signals
+/// it keeps for itself (32 and 33), and those stay ignored across exec too.
+#[cfg(target_arch = "x86_64")]
+const SYS_RT_SIGACTION: isize = 13;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+const SYS_RT_SIGACTION: isize = 134;
+#[cfg(any(target_arch = "x86", target_arch = "arm"))]
+const SYS_RT_SIGACTION: isize = 174;
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64",
+    target_arch = "x86",
+    target_arch = "arm"
+)))]
+compile_error!("libc: no rt_sigaction syscall number for this architecture");
+/// Size of the kernel's sigset_t.
+const KERNEL_SIGSET: isize = 8;
+const SIG_SETMASK: i32 = 2;
+const NSIG: i32 = 65;
+const F_GETFD: i32 = 1;
+const F_SETFD: i32 = 2;
+const FD_CLOEXEC: i32 = 1;
+const SOL_SOCKET: i32 = 1;
+const SO_PEERCRED: i32 = 17;
+const POLLERR: i16 = 0x8;
+const POLLHUP: i16 = 0x10;
+const CLONE_NEWNET: i32 = 0x4000_0000;
+
 pub fn kill_(pid: u32, sig: u32) -> i32 {
     unsafe { kill(pid, sig) }
 }
+
+pub fn geteuid_() -> u32 {
+    unsafe { geteuid() }
+}
+
+/// Moves the calling thread into the network namespace open at `fd`.
+pub fn setns_net_(fd: i32) -> i32 {
+    unsafe { setns(fd, CLONE_NEWNET) }
+}
+
+pub fn signal_default_(sig: i32) -> isize {
+    // handler SIG_DFL, no flags, empty mask, whatever the arch's field order
+    let action = [0u64; 4];
+    unsafe {
+        syscall(
+            SYS_RT_SIGACTION,
+            sig as isize,
+            action.as_ptr(),
+            std::ptr::null_mut::<u64>(),
+            KERNEL_SIGSET,
+        )
+    }
+}
+
+pub fn sigunblock_all_() -> i32 {
+    // large enough for both glibc (128 bytes) and bionic (8 bytes) sigset_t
+    let empty = [0u64; 16];
+    unsafe { sigprocmask(SIG_SETMASK, empty.as_ptr(), std::ptr::null_mut()) }
+}
+
+pub fn set_cloexec_(fd: i32) -> i32 {
+    unsafe {
+        let flags = fcntl(fd, F_GETFD);
+        if flags < 0 {
+            return flags;
+        }
+        fcntl(fd, F_SETFD, flags | FD_CLOEXEC)
+    }
+}
+
+/// (pid, uid) of the process on the other end of a unix socket.
+pub fn peer_cred_(fd: i32) -> Option<(i32, u32)> {
+    let mut cred = Ucred::default();
+    let mut len = std::mem::size_of::<Ucred>() as u32;
+    match unsafe { getsockopt(fd, SOL_SOCKET, SO_PEERCRED, &mut cred, &mut len) } {
+        0 => Some((cred.pid, cred.uid)),
+        _ => None,
+    }
+}
+
+/// Whether the peer of a unix stream closed it entirely. A client that only
+/// shut down its write side after sending the request does not count.
+pub fn hung_up_(fd: i32) -> bool {
+    let mut pollfd = PollFd {
+        fd,
+        events: 0,
+        revents: 0,
+    };
+    unsafe { poll(&mut pollfd, 1, 0) > 0 && pollfd.revents & (POLLHUP | POLLERR) != 0 }
+}
+
+/// Fds the daemon holds above stdio, collected before fork since the
+/// child may not allocate.
+pub fn inherited_fds() -> Vec<i32> {
+    match std::fs::read_dir("/proc/self/fd") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .filter(|fd| *fd > 2)
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Runs in the forked child right before exec: only async-signal-safe calls.
+pub fn reset_child(fds: &[i32]) {
+    for sig in 1..NSIG {
+        signal_default_(sig);
+    }
+    sigunblock_all_();
+    for fd in fds {
+        set_cloexec_(*fd);
+    }
+}
@@ -1,31 +1,434 @@
-use log::{LevelFilter, Metadata, Record};
+use log::{Level, LevelFilter, Metadata, Record};
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::fs::File;
-use std::io::Write;
-use std::sync::Mutex;
+use std::io::{ErrorKind, Write};
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{
+    KMSG_BURST, KMSG_INTERVAL_SEC, KMSG_LOG, KMSG_PATH, LOG_BATCH, LOG_FLUSH_POLL_MS,
+    LOG_FLUSH_SEC, LOG_QUEUE, LOG_RETRY_SEC, LOG_RING, STORED_MAX, SYSLOG_MAX, SYSLOG_PATH,
+    SYSLOG_SEND_MS,
+};
+use crate::{memory, stats};
+
+thread_local! {
+    static REQUEST: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Tags every record logged from this thread with a request id.
+pub fn set_request(id: Option<u64>) {
+    REQUEST.with(|request| request.set(id));
+}
+
+pub fn request() -> Option<u64> {
+    REQUEST.with(|request| request.get())
+}
+
+/// The last LOG_RING warn+ records, for `daemon#status?errors=N`.
+static RECENT: LazyLock<Mutex<VecDeque<String>>> =
+    LazyLock::new(|| Mutex::new(VecDeque::with_capacity(LOG_RING)));
+
+/// Up to `n` of the most recent warn+ records, oldest first.
+pub fn recent(n: usize) -> Vec<String> {
+    let recent = RECENT.lock().unwrap_or_else(PoisonError::into_inner);
+    recent
+        .iter()
+        .skip(recent.len().saturating_sub(n))
+        .cloned()
+        .collect()
+}
+
+fn remember(line: &str) {
+    let mut recent = RECENT.lock().unwrap_or_else(PoisonError::into_inner);
+    if recent.len() == LOG_RING {
+        recent.pop_front();
+    }
+    recent.push_back(memory::cap(line.to_string(), STORED_MAX));
+}
+
+/// Approximate bytes held by the remembered records.
+pub fn recent_bytes() -> usize {
+    let recent = RECENT.lock().unwrap_or_else(PoisonError::into_inner);
+    recent.capacity() * std::mem::size_of::<String>()
+        + recent.iter().map(String::capacity).sum::<usize>()
+}
+
+/// Secondary sink for warn+ records so they show up in dmesg when the log
+/// file is unusable. Opened on first use; a failed open is reported once and
+/// never retried.
+struct Kmsg {
+    file: Option<File>,
+    tried: bool,
+    window: Instant,
+    sent: u32,
+}
+
+static KMSG: LazyLock<Mutex<Kmsg>> = LazyLock::new(|| {
+    Mutex::new(Kmsg {
+        file: None,
+        tried: false,
+        window: Instant::now(),
+        sent: 0,
+    })
+});
+
+impl Kmsg {
+    fn write(&mut self, level: Level, line: &str) {
+        if !self.tried {
+            self.tried = true;
+            match std::fs::OpenOptions::new().write(true).open(KMSG_PATH) {
+                Ok(file) => self.file = Some(file),
+                Err(e) => eprintln!("[log] bad open {}: {}, kmsg disabled", KMSG_PATH, e),
+            }
+        }
+        let Some(file) = &mut self.file else {
+            return;
+        };
+
+        // stay well under the kernel's printk ratelimit
+        if self.window.elapsed() >= Duration::from_secs(KMSG_INTERVAL_SEC) {
+            self.window = Instant::now();
+            self.sent = 0;
+        }
+        if self.sent == KMSG_BURST {
+            return;
+        }
+        self.sent += 1;
+
+        let priority = match level {
+            Level::Error => 3,
+            _ => 4,
+        };
+        let _ = file.write_all(format!("<{}>dctl: {}\n", priority, line).as_bytes());
+    }
+}
+
+/// Whether this build can send to a syslog socket.
+const SYSLOG: bool = cfg!(not(target_os = "android"));
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Output {
+    File,
+    Syslog,
+    Both,
+}
+
+impl Output {
+    /// LOG_OUTPUT as configured; a mode this build can't serve is an error.
+    pub fn parse(output: &str) -> Result<Self, String> {
+        let parsed = match output {
+            "file" => Output::File,
+            "syslog" => Output::Syslog,
+            "both" => Output::Both,
+            _ => {
+                return Err(format!(
+                    "log: bad LOG_OUTPUT {:?}, expected file, syslog or both",
+                    output
+                ))
+            }
+        };
+        if parsed != Output::File && !SYSLOG {
+            return Err(format!(
+                "log: LOG_OUTPUT {} is not available on this platform",
+                output
+            ));
+        }
+        Ok(parsed)
+    }
+}
+
+/// Datagrams to the local syslog daemon (journald, rsyslog) at SYSLOG_PATH.
+/// A failed send reconnects once, since a restarted syslog daemon leaves the
+/// old socket dead; while it stays unreachable records are counted and
+/// dropped.
+struct Syslog {
+    socket: Option<UnixDatagram>,
+    dropped: u64,
+}
+
+impl Syslog {
+    fn connect() -> std::io::Result<UnixDatagram> {
+        let socket = UnixDatagram::unbound()?;
+        socket.set_write_timeout(Some(Duration::from_millis(SYSLOG_SEND_MS)))?;
+        socket.connect(SYSLOG_PATH)?;
+        Ok(socket)
+    }
+
+    /// RFC 3164 style `<PRI>dctl[PID]: text` in the daemon facility; the
+    /// receiver stamps time and host.
+    fn message(level: Level, text: &str) -> String {
+        let severity = match level {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        memory::cap(
+            format!(
+                "<{}>dctl[{}]: {}",
+                3 * 8 + severity,
+                std::process::id(),
+                text
+            ),
+            SYSLOG_MAX,
+        )
+    }
+
+    fn send(&mut self, message: &str) -> bool {
+        for _ in 0..2 {
+            if self.socket.is_none() {
+                self.socket = Syslog::connect().ok();
+            }
+            let Some(socket) = &self.socket else {
+                return false;
+            };
+            match socket.send(message.as_bytes()) {
+                Ok(_) => return true,
+                // the receiver is behind, not gone
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return false
+                }
+                Err(_) => self.socket = None,
+            }
+        }
+        false
+    }
+
+    fn write(&mut self, level: Level, text: &str) {
+        if self.dropped > 0 {
+            let notice = format!("log: syslog resumed, {} records dropped", self.dropped);
+            if !self.send(&Syslog::message(Level::Warn, &notice)) {
+                self.dropped += 1;
+                return;
+            }
+            self.dropped = 0;
+        }
+        if !self.send(&Syslog::message(level, text)) {
+            if self.dropped == 0 {
+                eprintln!(
+                    "[log] syslog: {} unreachable, dropping records",
+                    SYSLOG_PATH
+                );
+            }
+            self.dropped += 1;
+        }
+    }
+}
+
+enum Sink {
+    File(File),
+    /// LOG_PATH couldn't be opened; records go to stderr since then.
+    Stderr(Instant),
+}
+
+impl Sink {
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        match self {
+            Sink::File(file) => stats::track_write(file.write_all(buf)),
+            Sink::Stderr(_) => std::io::stderr().write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Sink::File(file) => file.flush(),
+            Sink::Stderr(_) => std::io::stderr().flush(),
+        }
+    }
+}
+
+enum Message {
+    Record {
+        level: Level,
+        /// `[level] text`, as written to the file.
+        line: String,
+        /// Without the level, which syslog carries in the priority.
+        text: String,
+    },
+    /// Answered once everything queued before it is written.
+    Flush(SyncSender<()>),
+}
+
+/// Records dropped because the queue was full.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+static HIGH_WATER: AtomicUsize = AtomicUsize::new(0);
+
+/// `log queue: dropped N, high-water M/LOG_QUEUE` for `daemon#info`.
+pub fn queue() -> String {
+    format!(
+        "log queue: dropped {}, high-water {}/{}",
+        DROPPED.load(Ordering::Relaxed),
+        HIGH_WATER.load(Ordering::Relaxed),
+        LOG_QUEUE
+    )
+}
 
 pub struct SimpleLogger {
     level: LevelFilter,
-    writable: Mutex<File>,
+    queue: SyncSender<Message>,
+}
+
+/// Owns the outputs and drains the queue into them, so a slow disk or
+/// syslog daemon holds up this thread only, never the one logging.
+struct Writer {
+    /// None with `LOG_OUTPUT = syslog`.
+    writable: Option<Arc<Mutex<Sink>>>,
+    syslog: Option<Syslog>,
+    /// DROPPED as last written to the log.
+    reported: u64,
+}
+
+impl Writer {
+    fn run(mut self, queue: Receiver<Message>) {
+        let mut batch = String::new();
+        let mut acks = Vec::new();
+        while let Ok(message) = queue.recv() {
+            let mut next = Some(message);
+            while let Some(message) = next {
+                match message {
+                    Message::Record { level, line, text } => {
+                        QUEUED.fetch_sub(1, Ordering::Relaxed);
+                        self.record(level, &line, &text, &mut batch);
+                    }
+                    Message::Flush(ack) => acks.push(ack),
+                }
+                next = match batch.len() < LOG_BATCH {
+                    true => queue.try_recv().ok(),
+                    false => None,
+                };
+            }
+
+            if let Some(writable) = &self.writable {
+                let mut writable = lock(writable);
+                let _ = writable.write_all(batch.as_bytes());
+                let _ = writable.flush();
+            }
+            batch.clear();
+            for ack in acks.drain(..) {
+                let _ = ack.send(());
+            }
+        }
+    }
+
+    fn record(&mut self, level: Level, line: &str, text: &str, batch: &mut String) {
+        let dropped = DROPPED.load(Ordering::Relaxed);
+        if dropped > self.reported {
+            let text = format!(
+                "log: queue full, {} records dropped",
+                dropped - self.reported
+            );
+            self.reported = dropped;
+            self.record(Level::Warn, &format!("[warn] {}", text), &text, batch);
+        }
+
+        batch.push_str(line);
+        batch.push('\n');
+        if let Some(syslog) = &mut self.syslog {
+            syslog.write(level, text);
+        }
+        if level <= Level::Warn && (KMSG_LOG || self.degraded()) {
+            KMSG.lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .write(level, line);
+        }
+    }
+
+    fn degraded(&self) -> bool {
+        stats::READ_ONLY.load(Ordering::Relaxed)
+            || self
+                .writable
+                .as_ref()
+                .is_some_and(|writable| matches!(*lock(writable), Sink::Stderr(_)))
+            || self
+                .syslog
+                .as_ref()
+                .is_some_and(|syslog| syslog.dropped > 0)
+    }
+}
+
+fn open(path: &str) -> std::io::Result<File> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+/// A panicking writer must not take logging down with it.
+fn lock(writable: &Mutex<Sink>) -> MutexGuard<'_, Sink> {
+    writable.lock().unwrap_or_else(PoisonError::into_inner)
 }
 
 impl SimpleLogger {
-    pub fn init(level: LevelFilter, path: &str) -> Result<(), log::SetLoggerError> {
+    pub fn init(level: LevelFilter, path: &str, output: Output) -> Result<(), log::SetLoggerError> {
         log::set_max_level(level);
-        log::set_boxed_logger(SimpleLogger::new(
-            level,
-            std::fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(path)
-                .expect("[log] bad open file"),
-        ))
+
+        let writable = match output {
+            Output::Syslog => None,
+            Output::File | Output::Both => Some(SimpleLogger::file(path)),
+        };
+        let syslog = match output {
+            Output::File => None,
+            Output::Syslog | Output::Both => Some(Syslog {
+                socket: None,
+                dropped: 0,
+            }),
+        };
+
+        let (queue, records) = mpsc::sync_channel(LOG_QUEUE);
+        let writer = Writer {
+            writable,
+            syslog,
+            reported: 0,
+        };
+        thread::spawn(move || writer.run(records));
+
+        log::set_boxed_logger(Box::new(SimpleLogger { level, queue }))
     }
 
-    fn new(level: LevelFilter, writable: std::fs::File) -> Box<SimpleLogger> {
-        Box::new(SimpleLogger {
-            level,
-            writable: Mutex::new(writable),
-        })
+    fn file(path: &str) -> Arc<Mutex<Sink>> {
+        let sink = match open(path) {
+            Ok(file) => Sink::File(file),
+            Err(e) => {
+                eprintln!("[log] bad open file {}: {}, using stderr", path, e);
+                Sink::Stderr(Instant::now())
+            }
+        };
+        let fallback = matches!(sink, Sink::Stderr(_));
+        let writable = Arc::new(Mutex::new(sink));
+
+        if fallback {
+            SimpleLogger::retry(Arc::clone(&writable), path.to_string());
+        }
+        writable
+    }
+
+    /// Keeps trying to open the real log file and switches over once it can.
+    fn retry(writable: Arc<Mutex<Sink>>, path: String) {
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(LOG_RETRY_SEC));
+
+            if let Ok(file) = open(&path) {
+                let mut sink = lock(&writable);
+                if let Sink::Stderr(since) = *sink {
+                    *sink = Sink::File(file);
+                    let _ = sink.write_all(
+                        format!(
+                            "[warn] log: resumed, records of the last {}s went to stderr\n",
+                            since.elapsed().as_secs()
+                        )
+                        .as_bytes(),
+                    );
+                }
+                break;
+            }
+        });
     }
 }
 
@@ -36,19 +439,42 @@ impl log::Log for SimpleLogger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            let mut writable = self.writable.lock().unwrap();
-            let _ = writable.write_all(
-                format!(
-                    "[{}] {}\n",
-                    record.level().as_str().to_lowercase(),
-                    record.args()
-                )
-                .as_bytes(),
-            );
+            let tag = request().map_or(String::new(), |id| format!("[#{}] ", id));
+            let text = format!("{}{}", tag, record.args());
+            let line = format!("[{}] {}", record.level().as_str().to_lowercase(), text);
+            if record.level() <= Level::Warn {
+                remember(&line);
+            }
+
+            let level = record.level();
+            let queued = QUEUED.fetch_add(1, Ordering::Relaxed) + 1;
+            match self.queue.try_send(Message::Record { level, line, text }) {
+                Ok(()) => {
+                    HIGH_WATER.fetch_max(queued, Ordering::Relaxed);
+                }
+                Err(_) => {
+                    QUEUED.fetch_sub(1, Ordering::Relaxed);
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+            }
         }
     }
 
+    /// Waits up to LOG_FLUSH_SEC for everything queued so far to be written.
     fn flush(&self) {
-        let _ = self.writable.lock().unwrap().flush();
+        let deadline = Instant::now() + Duration::from_secs(LOG_FLUSH_SEC);
+        let (ack, done) = mpsc::sync_channel(1);
+        let mut message = Message::Flush(ack);
+        loop {
+            match self.queue.try_send(message) {
+                Ok(()) => break,
+                Err(TrySendError::Full(back)) if Instant::now() < deadline => {
+                    message = back;
+                    thread::sleep(Duration::from_millis(LOG_FLUSH_POLL_MS));
+                }
+                Err(_) => return,
+            }
+        }
+        let _ = done.recv_timeout(deadline.saturating_duration_since(Instant::now()));
     }
 }
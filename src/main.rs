@@ -1,150 +1,1844 @@
-use log::{error, info, LevelFilter};
+use log::{error, info, warn, LevelFilter};
 use std::collections::HashMap;
 use std::fmt::{self, Display};
 use std::fs::File;
-use std::io::{prelude::*, BufReader, Lines};
+use std::io::{prelude::*, ErrorKind};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::process::Command;
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
-use std::sync::{Arc, Mutex};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard};
 use std::thread::{self, JoinHandle};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+mod accept;
+mod autostart;
+mod clock;
 mod config;
+mod definition;
+mod doctor;
+mod events;
+mod extension;
+mod failure;
+mod fds;
+mod hooks;
+mod idem;
 mod libc;
 mod logger;
+mod memory;
+mod mounts;
+mod netns;
+mod output;
+mod proxy;
+mod pump;
+mod queue;
+mod repair;
+mod restarts;
+mod scaffold;
+mod secrets;
+mod signals;
+mod snapshot;
+mod stats;
+mod status;
+mod trace;
 
 use config::*;
-use libc::kill_;
+use definition::{Restart, ServiceDefinition};
+use failure::{Code, Failure};
+use libc::{hung_up_, inherited_fds, kill_, peer_cred_, reset_child};
 use logger::SimpleLogger;
+use pump::{pump, Limiter};
+use status::StatusSnapshot;
 
-struct ConfigReader(Lines<BufReader<File>>);
+struct ServiceStack {
+    stack: RwLock<HashMap<String, ArcService>>,
+    started: Instant,
+}
 
-impl ConfigReader {
-    fn new(fpath: &str) -> Self {
-        Self(BufReader::new(File::open(fpath).expect("bad open file")).lines())
+impl Display for ServiceStack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.render(None, false))
     }
 }
 
-impl Iterator for ConfigReader {
-    type Item = (String, String, Vec<String>);
+impl ServiceStack {
+    fn new(stack: HashMap<String, ArcService>) -> Self {
+        Self {
+            stack: RwLock::new(stack),
+            started: Instant::now(),
+        }
+    }
+
+    /// Snapshots of the services passing `features`, sorted by name; see
+    /// `StatusSnapshot::passes`.
+    fn snapshots(&self, features: Option<&str>) -> Vec<StatusSnapshot> {
+        self.entries()
+            .iter()
+            .map(|(name, service)| StatusSnapshot::capture(name, service))
+            .filter(|snapshot| snapshot.passes(features))
+            .collect()
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        for line in &mut self.0 {
-            let line = line.expect("service: bad load service(of config file)");
-            let parts: Vec<&str> = line.splitn(3, ' ').collect();
-            let mut args = Vec::new();
-            match parts.len() {
-                0 | 1 => continue,
-                2 => (),
-                _ => {
-                    args.extend(
-                        parts[2]
-                            .split_whitespace()
-                            .map(|arg| arg.to_string())
-                            .collect::<Vec<String>>(),
+    /// `daemon#status` lines of the services passing `features`; `long` adds
+    /// an indented description/URL line under each service that has one.
+    fn render(&self, features: Option<&str>, long: bool) -> String {
+        let mut status_queue: Vec<String> = Vec::new();
+        for snapshot in self.snapshots(features) {
+            status_queue.push(match long {
+                true => snapshot.long(),
+                false => snapshot.plain(&[]),
+            });
+        }
+        status_queue.join("\n")
+    }
+
+    /// `daemon#status?porcelain`: `name activity pid spawns` per service,
+    /// sorted by name. Scripts and `status --diff` rely on it, so fields are
+    /// only ever appended.
+    fn porcelain(&self, features: Option<&str>) -> String {
+        self.snapshots(features)
+            .iter()
+            .map(StatusSnapshot::porcelain)
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// `daemon#status?json`: a JSON array of every service, one per line,
+    /// sorted by name.
+    fn json(&self, features: Option<&str>) -> String {
+        let objects: Vec<String> = self
+            .snapshots(features)
+            .iter()
+            .map(|snapshot| format!("  {}", snapshot.json()))
+            .collect();
+        match objects.is_empty() {
+            true => String::from("[]"),
+            false => format!("[\n{}\n]", objects.join(",\n")),
+        }
+    }
+
+    /// `message` with the secrets of every service masked, for traces.
+    fn mask(&self, message: &str) -> String {
+        let stack = self.services();
+        let listed: Vec<&str> = stack
+            .values()
+            .flat_map(|service| service.0.definition.secret_env())
+            .collect();
+        let values: Vec<String> = stack
+            .values()
+            .flat_map(|service| service.0.secrets())
+            .collect();
+        secrets::mask_options(message, &listed, &values)
+    }
+
+    fn services(&self) -> RwLockReadGuard<'_, HashMap<String, ArcService>> {
+        self.stack.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The service `name`, cloned out so the stack lock is released before
+    /// any work on it: stops, waits and /proc reads only need the service's
+    /// own locks, and a reload or `run` shouldn't queue up behind them.
+    fn service(&self, name: &str) -> Option<ArcService> {
+        self.services().get(name).cloned()
+    }
+
+    /// Every service, cloned out like `service` and sorted by name.
+    fn entries(&self) -> Vec<(String, ArcService)> {
+        let mut entries: Vec<(String, ArcService)> = self
+            .services()
+            .iter()
+            .map(|(name, service)| (name.clone(), service.clone()))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    fn init(fpath: &str) -> Result<Self, String> {
+        Ok(ServiceStack::new(ServiceStack::load(fpath)?))
+    }
+
+    fn load(fpath: &str) -> Result<HashMap<String, ArcService>, String> {
+        Ok(definition::load(fpath)?
+            .into_iter()
+            .enumerate()
+            .map(|(order, definition)| {
+                info!("service: {}: {}", definition.name, definition);
+                for key in definition.leaked() {
+                    warn!(
+                        "service: {}: the value of secret {} is in the arguments, masked in output",
+                        definition.name, key
                     );
                 }
+                let service = ArcService::new(definition);
+                service.0.order.store(order, Ordering::Relaxed);
+                (service.0.definition.name.clone(), service)
+            })
+            .collect())
+    }
+
+    fn start(&self, name: &str, overrides: Overrides) -> String {
+        let stack = self.services();
+        let mut chain = vec![name.to_string()];
+        if let Some(cause) = ServiceStack::broken(&stack, &mut chain) {
+            let root = chain.last().cloned().unwrap_or_default();
+            let chain = chain.join(" → ");
+            events::record(format!(
+                "start {} failed: {}, {}: {}",
+                name, chain, root, cause
+            ));
+            return format!("service: {}: {} ({}), can't start", root, cause, chain);
+        }
+        let service = stack.get(name).cloned();
+        drop(stack);
+        match service {
+            Some(service) if service.running() && !overrides.is_empty() => {
+                String::from("service: already running, overrides not applied to")
+            }
+            Some(service) => service
+                .start_with(name, Origin::Manual, overrides)
+                .to_string(),
+            None => format!("service: can't find {}", name),
+        }
+    }
+
+    /// Follows `Requires=` from the last name of `chain` and leaves the path
+    /// to the first required service that can't come up in `chain`, returning
+    /// why. Services merely stopped don't break the chain.
+    fn broken(stack: &HashMap<String, ArcService>, chain: &mut Vec<String>) -> Option<String> {
+        let service = match stack.get(chain.last()?) {
+            Some(service) => service,
+            None => return Some(String::from("not configured")),
+        };
+        if chain.len() > 1 {
+            if let Some(reason) = service.0.status().state.reason() {
+                return Some(reason.to_string());
+            }
+        }
+        for required in service.0.definition.requires() {
+            if chain.iter().any(|name| name == required) {
+                continue;
+            }
+            chain.push(required.to_string());
+            if let Some(cause) = ServiceStack::broken(stack, chain) {
+                return Some(cause);
+            }
+            chain.pop();
+        }
+        None
+    }
+
+    /// `why#name`: what is keeping `name` from running, or what it is doing
+    /// instead, put together from state the daemon already keeps.
+    fn why(&self, name: &str) -> String {
+        let Some(service) = self.service(name) else {
+            return format!("service: can't find {}", name);
+        };
+        let (state, since) = {
+            let status = service.0.status();
+            (status.state.clone(), status.since.elapsed().as_secs())
+        };
+        let definition = &service.0.definition;
+        let mut lines = vec![format!("{} is {} for {}s", name, service.activity(), since)];
+
+        match &state {
+            State::Running => lines.push(format!(
+                "running as pid {}",
+                service.0.pid.load(Ordering::Acquire)
+            )),
+            State::Failed(failure) => {
+                lines.push(format!("failed: {} ({})", failure.reason(), failure.code()));
+                match failure.code() {
+                    Code::StartLimit => lines.push(format!(
+                        "start limit: crashed {} times in a row, not restarted until started again",
+                        START_LIMIT
+                    )),
+                    _ => lines.push(String::from("not restarted until started again")),
+                }
+            }
+            State::Waiting(reason) => {
+                lines.push(format!("waiting: {}, spawned once that clears", reason))
+            }
+            State::Retrying { next, attempt: 0 } => lines.push(format!(
+                "exited, restarting in {}s (RestartSec={})",
+                next.saturating_duration_since(Instant::now()).as_secs(),
+                definition.restart_sec()
+            )),
+            State::Retrying { next, attempt } => lines.push(format!(
+                "crashed quickly, backoff: attempt {} of {} in {}s",
+                attempt,
+                START_LIMIT,
+                next.saturating_duration_since(Instant::now()).as_secs()
+            )),
+            State::Stopping { grace: true } => {
+                lines.push(String::from("stopping: PRESTOP_SIGNAL sent, grace running"))
+            }
+            State::Stopping { grace: false } => {
+                lines.push(String::from("stopping: waiting for the process to exit"))
+            }
+            State::Stopped => {
+                let exit = *service.0.exit.lock().unwrap();
+                let policy = definition.restart();
+                match exit {
+                    // an exit the policy respawns only ends in Stopped by request
+                    Some(exit)
+                        if policy == Restart::Always
+                            || (policy == Restart::OnFailure && !exit.success()) =>
+                    {
+                        lines.push(format!("stopped on request, last spawn ended: {}", exit))
+                    }
+                    Some(exit) => {
+                        let policy = match policy {
+                            Restart::Always => "always",
+                            Restart::OnFailure => "on-failure",
+                            Restart::Never => "never",
+                        };
+                        lines.push(format!("last spawn ended: {}, Restart={}", exit, policy));
+                    }
+                    None if service.0.spawns.load(Ordering::Relaxed) == 0 => {
+                        lines.push(String::from("never started since the daemon came up"))
+                    }
+                    None => lines.push(String::from("stopped on request")),
+                }
+            }
+        }
+
+        if !matches!(state, State::Running) {
+            let mut chain = vec![name.to_string()];
+            if let Some(cause) = ServiceStack::broken(&self.services(), &mut chain) {
+                let root = chain.last().cloned().unwrap_or_default();
+                lines.push(format!(
+                    "blocked by {}: {} ({})",
+                    root,
+                    cause,
+                    chain.join(" → ")
+                ));
+            }
+        }
+        match autostart::read() {
+            Some(enabled) if !enabled.iter().any(|enabled| enabled == name) => lines.push(
+                String::from("not enabled, the daemon won't start it at boot"),
+            ),
+            _ => (),
+        }
+        if *service.0.transient.lock().unwrap() == Some(false) {
+            lines.push(String::from("transient, forgotten once it stops"));
+        }
+        let queued = queue::depth(name);
+        if queued != 0 {
+            lines.push(format!("queue: {} requests running or waiting", queued));
+        }
+
+        for event in events::about(name, WHY_EVENTS) {
+            lines.push(format!("event: {}", event));
+        }
+        lines.join("\n")
+    }
+
+    /// `deps#name`: the `Requires=` tree of `name`, one service per line
+    /// indented by depth, with its activity and why it is broken if it is.
+    fn deps(&self, name: &str) -> String {
+        fn walk(
+            stack: &HashMap<String, ArcService>,
+            name: &str,
+            path: &mut Vec<String>,
+            lines: &mut Vec<String>,
+        ) {
+            let indent = "  ".repeat(path.len());
+            let Some(service) = stack.get(name) else {
+                return lines.push(format!("{}{} broken: not configured", indent, name));
+            };
+            let state = service.0.status().state.clone();
+            let line = match state.reason() {
+                Some(reason) if !path.is_empty() => {
+                    format!("{}{} broken: {}", indent, name, reason)
+                }
+                _ => format!("{}{} {}", indent, name, service.activity()),
             };
+            lines.push(line);
 
-            let name = parts[0];
-            let command = parts[1];
+            path.push(name.to_string());
+            for required in service.0.definition.requires() {
+                match path.iter().any(|seen| seen == required) {
+                    true => lines.push(format!("{}  {} (cycle)", indent, required)),
+                    false => walk(stack, required, path, lines),
+                }
+            }
+            path.pop();
+        }
+
+        let stack = self.services();
+        if !stack.contains_key(name) {
+            return format!("service: can't find {}", name);
+        }
+        let mut lines = Vec::new();
+        walk(&stack, name, &mut Vec::new(), &mut lines);
+        lines.join("\n")
+    }
 
-            info!("service: {}: {} {}", name, command, args.join(" "));
+    /// Stops `name` with `signal`, its STOPSIGNAL if None.
+    fn stop(&self, name: &str, signal: Option<u32>) -> String {
+        match self.service(name) {
+            Some(service) => {
+                service.0.blame.lock().unwrap().stale = true;
+                let signal = signal.unwrap_or(service.0.definition.stop_signal());
+                service.stop_with(signal);
+                service.to_string()
+            }
+            None => format!("service: can't find {}", name),
+        }
+    }
 
-            return Some((name.to_string(), command.to_string(), args));
+    fn restart(&self, name: &str) -> String {
+        match self.service(name) {
+            Some(service) => {
+                service.0.blame.lock().unwrap().stale = true;
+                service.stop().start_as(name, Origin::Manual).to_string()
+            }
+            None => format!("service: can't find {}", name),
         }
+    }
 
-        None
+    /// `restart --if-changed`: bounces `name` only if its config line or its
+    /// executable changed since the running incarnation was spawned.
+    fn restart_if_changed(&self, fpath: &str, name: &str) -> String {
+        let fresh = match definition::load(fpath) {
+            Ok(fresh) => fresh,
+            Err(e) => return e,
+        };
+        let Some(service) = self.service(name) else {
+            return format!("service: can't find {}", name);
+        };
+        let fresh = fresh.into_iter().find(|definition| definition.name == name);
+
+        match service.change(fresh.as_ref()) {
+            Some(change) => {
+                info!("service: restart: {} ({})", name, change);
+                let service = self.bounce(name, service, fresh);
+                format!("{} {}", service, name)
+            }
+            None => String::from("unchanged, skipped"),
+        }
     }
-}
 
-struct ServiceStack {
-    stack: HashMap<String, ArcService>,
-}
+    /// `daemon#restart-changed`: `restart --if-changed` over every running
+    /// service, one `restarted`/`skipped` line each.
+    fn restart_changed(&self, fpath: &str) -> String {
+        let fresh: HashMap<String, ServiceDefinition> = match definition::load(fpath) {
+            Ok(fresh) => fresh
+                .into_iter()
+                .map(|definition| (definition.name.clone(), definition))
+                .collect(),
+            Err(e) => return e,
+        };
+
+        let lines: Vec<String> = self
+            .entries()
+            .into_iter()
+            .filter(|(_, service)| service.running())
+            .map(|(name, service)| {
+                let fresh = fresh.get(&name).cloned();
+                match service.change(fresh.as_ref()) {
+                    Some(change) => {
+                        self.bounce(&name, service, fresh);
+                        format!("restarted {} ({})", name, change)
+                    }
+                    None => format!("skipped {} (unchanged)", name),
+                }
+            })
+            .collect();
+        info!("service: restart-changed: {}", lines.join(", "));
 
-impl Display for ServiceStack {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut status_queue: Vec<String> = Vec::new();
-        for (k, v) in &self.stack {
-            status_queue.push(format!("{} {}", v, k));
+        match lines.is_empty() {
+            true => String::from("restart-changed: nothing running"),
+            false => lines.join("\n"),
         }
-        write!(f, "{}", status_queue.join("\n"))
     }
-}
 
-impl ServiceStack {
-    fn new(stack: HashMap<String, ArcService>) -> Self {
-        Self { stack }
+    /// Restarts `service`, swapping in `fresh` if the definition differs.
+    /// The stop runs without the stack lock; it is taken only to put the
+    /// replacement in, and only if `service` is still the one under `name`.
+    fn bounce(
+        &self,
+        name: &str,
+        service: ArcService,
+        fresh: Option<ServiceDefinition>,
+    ) -> ArcService {
+        service.0.blame.lock().unwrap().stale = true;
+        service.stop();
+
+        let service = match fresh {
+            Some(fresh) if fresh != service.0.definition => {
+                let replacement = ArcService::new(fresh);
+                *replacement.0.origin.lock().unwrap() = service.origin();
+                let mut stack = self.stack.write().unwrap_or_else(PoisonError::into_inner);
+                match stack.get(name) {
+                    Some(current) if Arc::ptr_eq(&current.0, &service.0) => {
+                        stack.insert(name.to_string(), replacement.clone());
+                        replacement
+                    }
+                    // replaced or removed meanwhile, by a reload
+                    _ => return service,
+                }
+            }
+            _ => service,
+        };
+        let origin = service.origin();
+        service.start_as(name, origin);
+        service
+    }
+
+    fn status(&self, name: &str) -> String {
+        match self.service(name) {
+            Some(service) => StatusSnapshot::capture(name, &service).plain(&["name"]),
+            None => format!("service: can't find {}", name),
+        }
+    }
+
+    /// `status --full`: the status line plus what the last spawn executed.
+    fn status_full(&self, name: &str) -> String {
+        match self.service(name) {
+            Some(service) => {
+                let mut lines = vec![self.status(name)];
+                if let State::Failed(failure) = &service.0.status().state {
+                    lines.push(format!("failure: {}", failure.code()));
+                }
+                match &*service.0.executed.lock().unwrap() {
+                    Some(executed) => {
+                        lines.push(format!("argv: {}", executed.argv));
+                        lines.push(format!("env: {}", executed.env));
+                    }
+                    None => lines.push(String::from("argv: never spawned")),
+                }
+                let queued = queue::depth(name);
+                if queued != 0 {
+                    lines.push(format!("queue: {}", queued));
+                }
+                let pid = service.0.pid.load(Ordering::Acquire);
+                if pid != 0 {
+                    let (usage, high) = fds::usage(pid);
+                    if high {
+                        warn!("service: {}: {} fds open, near its limit", name, usage);
+                        lines.push(format!("fds: {}, near limit", usage));
+                    } else {
+                        lines.push(format!("fds: {}", usage));
+                    }
+                }
+                lines.join("\n")
+            }
+            None => format!("service: can't find {}", name),
+        }
+    }
+
+    /// The service whose `Handles` prefix covers `verb`, longest prefix first.
+    fn handler(&self, verb: &str) -> Option<String> {
+        self.services()
+            .iter()
+            .filter_map(|(name, service)| Some((name, service.0.definition.value("Handles")?)))
+            .filter(|(_, prefix)| extension::matches(prefix, verb))
+            .max_by_key(|(_, prefix)| prefix.len())
+            .map(|(name, _)| name.clone())
     }
 
-    fn init(fpath: &str) -> Self {
-        let config_hashmap: HashMap<String, ArcService> = ConfigReader::new(fpath)
-            .map(|(name, command, args)| (name, ArcService::new(command, args)))
+    /// `enable`/`disable` of comma separated names, rewriting the autostart
+    /// file once for all of them. Enabling checks `Requires=`: a required
+    /// service that won't be enabled is a warning, or a refusal with `strict`.
+    fn set_enabled(&self, names: &str, enable: bool, strict: bool, dry_run: bool) -> String {
+        let stack = self.services();
+        let requested: Vec<&str> = names.split(',').filter(|name| !name.is_empty()).collect();
+
+        // may run again on a file changed meanwhile, so it starts afresh
+        let updated = autostart::update(|current| {
+            let mut enabled = current.unwrap_or_else(|| {
+                let mut all: Vec<String> = stack.keys().cloned().collect();
+                all.sort();
+                all
+            });
+            let before = enabled.clone();
+            let mut lines = Vec::new();
+
+            for name in &requested {
+                let Some(service) = stack.get(*name) else {
+                    lines.push(format!("service: can't find {}", name));
+                    continue;
+                };
+                let listed = enabled.iter().any(|enabled| enabled == name);
+                match (enable, listed) {
+                    (true, true) => lines.push(format!("{}: already enabled", name)),
+                    (false, false) => lines.push(format!("{}: already disabled", name)),
+                    (false, true) => {
+                        enabled.retain(|enabled| enabled != name);
+                        lines.push(format!("disabled {}", name));
+                    }
+                    (true, false) => {
+                        let missing: Vec<&str> = service
+                            .0
+                            .definition
+                            .requires()
+                            .filter(|required| {
+                                !enabled.iter().any(|enabled| enabled == required)
+                                    && !requested.contains(required)
+                            })
+                            .collect();
+                        if strict && !missing.is_empty() {
+                            lines.push(format!(
+                                "refused {}: requires {}, not enabled",
+                                name,
+                                missing.join(", ")
+                            ));
+                            continue;
+                        }
+                        for required in missing {
+                            lines.push(format!(
+                                "warn: {} requires {}, which is not enabled",
+                                name, required
+                            ));
+                        }
+                        enabled.push(name.to_string());
+                        lines.push(format!("enabled {}", name));
+                    }
+                }
+            }
+
+            if dry_run {
+                lines.push(String::from("would write:"));
+                lines.push(autostart::render(&enabled).trim_end().to_string());
+                return (None, (lines, false));
+            }
+            let changed = enabled != before;
+            (changed.then_some(enabled), (lines, changed))
+        });
+
+        match updated {
+            Ok((lines, changed)) => {
+                if changed {
+                    info!("autostart: {}", lines.join(", "));
+                }
+                lines.join("\n")
+            }
+            Err(e) => {
+                error!("{}", e);
+                e
+            }
+        }
+    }
+
+    /// `wait#name`: blocks until the service is out of activating (Retrying,
+    /// Waiting) and answers like `is-active`, with the reason when failed.
+    /// Running counts once it lasted RESTART_SEC, the bar for not being a crash;
+    /// a oneshot is waited for until it completed or failed.
+    /// None once `hung_up` reports the client gone, which ends the wait.
+    fn wait(&self, name: &str, hung_up: impl Fn() -> bool) -> Option<String> {
+        let Some(service) = self.service(name) else {
+            return Some(format!("service: can't find {}", name));
+        };
+
+        stats::WAITERS.fetch_add(1, Ordering::Relaxed);
+        let outcome = loop {
+            let (state, since) = {
+                let status = service.0.status();
+                (status.state.clone(), status.since)
+            };
+            match &state {
+                State::Retrying { .. } | State::Waiting(_) => (),
+                State::Running if service.0.definition.oneshot() => (),
+                State::Running if since.elapsed() <= Duration::from_secs(RESTART_SEC) => (),
+                // supervisor spawned but not through its first spawn yet
+                State::Stopped if service.running() => (),
+                State::Failed(failure) => break Some(format!("failed: {}", failure)),
+                _ => break Some(service.activity().to_string()),
+            }
+            if hung_up() {
+                info!("service: wait: {}: client went away", name);
+                break None;
+            }
+            thread::sleep(Duration::from_millis(SUPERVISE_TICK_MS));
+        };
+        stats::WAITERS.fetch_sub(1, Ordering::Relaxed);
+        outcome
+    }
+
+    /// `show#name`: the definition after specifier expansion, one field per line.
+    /// With `running`, also the overrides and argv of the live incarnation.
+    fn show(&self, name: &str, running: bool) -> String {
+        let Some(service) = self.service(name) else {
+            return format!("service: can't find {}", name);
+        };
+
+        let mut lines = vec![service.0.definition.show()];
+        if running {
+            let overrides = service.0.overrides.lock().unwrap().clone();
+            if !overrides.is_empty() {
+                let overrides = secrets::mask(&overrides.to_string(), &service.0.secrets());
+                lines.push(format!("overrides: {}", overrides));
+            }
+            if let Some(executed) = &*service.0.executed.lock().unwrap() {
+                lines.push(format!("running: {}", executed.argv));
+            }
+            let pid = service.0.pid.load(Ordering::Acquire);
+            if let (Some(name), Some(running)) = (service.0.definition.netns(), netns::of(pid)) {
+                let path = netns::path(name);
+                match netns::id(&path) {
+                    Some(id) if id == running => lines.push(format!("netns: {} ({})", name, id)),
+                    _ => lines.push(format!("netns: {}, not {}", running, path.display())),
+                }
+            }
+        }
+        lines.join("\n")
+    }
+
+    fn is_active(&self, name: &str) -> String {
+        match self.service(name) {
+            Some(service) => service.activity().to_string(),
+            None => String::from("unknown"),
+        }
+    }
+
+    /// Gives up on a service that is between respawns instead of waiting for
+    /// its start limit.
+    fn cancel_retry(&self, name: &str) -> String {
+        match self.service(name) {
+            Some(service) if matches!(service.0.status().state, State::Retrying { .. }) => {
+                info!("service: cancel-retry: {}", name);
+                format!("{} {}", service.stop(), name)
+            }
+            Some(_) => format!("service: {} is not retrying", name),
+            None => format!("service: can't find {}", name),
+        }
+    }
+
+    /// One `status name` line per comma separated name, in request order,
+    /// leaving out services that don't pass `features`. Unknown names are
+    /// always listed.
+    fn status_many(&self, names: &str, features: Option<&str>) -> String {
+        names
+            .split(',')
+            .filter_map(|name| match self.service(name) {
+                Some(service) => {
+                    let snapshot = StatusSnapshot::capture(name, &service);
+                    snapshot.passes(features).then(|| snapshot.plain(&[]))
+                }
+                None => Some(format!("{} - unknown", name)),
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Starts every service, at most AUTOSTART_CONCURRENCY still launching
+    /// (not yet running, failed or waiting) at any time.
+    fn autostart(&self) {
+        let enabled = autostart::read();
+        let mut services: Vec<(String, ArcService)> = self
+            .services()
+            .iter()
+            .filter(|(name, _)| {
+                enabled
+                    .as_ref()
+                    .is_none_or(|enabled| enabled.contains(name))
+            })
+            .map(|(name, service)| (name.clone(), service.clone()))
             .collect();
+        services.sort_by_key(|(_, service)| service.0.order.load(Ordering::Relaxed));
+
+        let slots = Slots::new(AUTOSTART_CONCURRENCY);
+        let begin = Instant::now();
+        for (name, service) in &services {
+            let slot = slots.acquire();
+            service.0.blame.lock().unwrap().begin = Some(begin);
+            *service.0.slot.lock().unwrap() = Some(slot);
+            service.start_as(name, Origin::Autostart);
+        }
+        // every slot back means every service has settled
+        let settled: Vec<Slot> = (0..AUTOSTART_CONCURRENCY)
+            .map(|_| slots.acquire())
+            .collect();
+        drop(settled);
+
+        info!(
+            "service: autostarted {} services, concurrency {}, took {}ms",
+            services.len(),
+            AUTOSTART_CONCURRENCY,
+            begin.elapsed().as_millis()
+        );
+    }
 
-        ServiceStack::new(config_hashmap)
+    /// `name millis [stale]` per autostarted service, slowest first, then the
+    /// total autostart wall time.
+    fn blame(&self) -> String {
+        let stack = self.services();
+        let mut entries: Vec<(&String, Duration, bool)> = stack
+            .iter()
+            .filter_map(|(name, service)| {
+                let blame = service.0.blame.lock().unwrap();
+                blame.took.map(|took| (name, took, blame.stale))
+            })
+            .collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.1));
+
+        let total = entries.first().map_or(0, |entry| entry.1.as_millis());
+        let mut lines: Vec<String> = entries
+            .iter()
+            .map(|(name, took, stale)| {
+                let stale = if *stale { " stale" } else { "" };
+                format!("{} {}{}", name, took.as_millis(), stale)
+            })
+            .collect();
+        lines.push(format!("total {}", total));
+        lines.join("\n")
+    }
+
+    fn info(&self) -> String {
+        let stack = self.services();
+        [
+            format!("uptime: {}s", self.started.elapsed().as_secs()),
+            format!("services: {}", stack.len()),
+            format!("waiters: {}", stats::WAITERS.load(Ordering::Relaxed)),
+            format!(
+                "accept errors: fd {}, transient {}, fatal {}",
+                stats::ACCEPT_FD_ERRORS.load(Ordering::Relaxed),
+                stats::ACCEPT_TRANSIENT_ERRORS.load(Ordering::Relaxed),
+                stats::ACCEPT_FATAL_ERRORS.load(Ordering::Relaxed)
+            ),
+            format!(
+                "supervisor deaths: {}",
+                stats::SUPERVISOR_DEATHS.load(Ordering::Relaxed)
+            ),
+            format!(
+                "idempotency hits: {}",
+                stats::IDEM_HITS.load(Ordering::Relaxed)
+            ),
+            memory::render(&[
+                ("services", stack.values().map(ArcService::bytes).sum()),
+                ("events", events::bytes()),
+                ("log ring", logger::recent_bytes()),
+                ("idempotency", idem::bytes()),
+            ]),
+            logger::queue(),
+            restarts::render(),
+            format!(
+                "filesystem: {}",
+                match stats::READ_ONLY.load(Ordering::Relaxed) {
+                    true => "read-only",
+                    false => "ok",
+                }
+            ),
+        ]
+        .join("\n")
     }
 
-    fn start(&self, name: &str) -> String {
-        match self.stack.get(name) {
-            Some(service) => service.start().to_string(),
-            None => String::from("service: can't find {name}"),
+    /// `daemon#config`: `name value` of every daemon setting sorted
+    /// by name, secrets masked, then `unknown: service key` for directives
+    /// in the config file that nothing reads.
+    fn config(&self) -> String {
+        let mut lines: Vec<String> = config::effective()
+            .into_iter()
+            .map(|(name, value)| match secrets::secret(name, &[]) {
+                true => format!("{} {}", name, secrets::MASK),
+                false => format!("{} {}", name, value),
+            })
+            .collect();
+        for (name, service) in self.entries() {
+            for key in service.0.definition.unknown() {
+                lines.push(format!("unknown: {} {}", name, key));
+            }
         }
+        lines.join("\n")
+    }
+
+    /// `daemon#fdtop`: `count name` of the `top` running services with the
+    /// most open fds; unreadable counts are listed last as `?`.
+    fn fdtop(&self, top: usize) -> String {
+        let entries = self.entries();
+        let mut counts: Vec<(Option<usize>, &String)> = entries
+            .iter()
+            .map(|(name, service)| (service.0.pid.load(Ordering::Acquire), name))
+            .filter(|(pid, _)| *pid != 0)
+            .map(|(pid, name)| (fds::count(pid), name))
+            .collect();
+        counts.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+
+        counts
+            .iter()
+            .take(top)
+            .map(|(count, name)| match count {
+                Some(count) => format!("{} {}", count, name),
+                None => format!("? {}", name),
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// `collisions` findings of the current stack; the argv is the one the
+    /// last spawn actually ran.
+    fn collisions(&self) -> Vec<doctor::Finding> {
+        let stack = self.services();
+        let argvs: Vec<(&String, Option<String>)> = stack
+            .iter()
+            .map(|(name, service)| {
+                let argv = match service.running() {
+                    true => service
+                        .0
+                        .executed
+                        .lock()
+                        .unwrap()
+                        .as_ref()
+                        .map(|e| e.argv.clone()),
+                    false => None,
+                };
+                (name, argv)
+            })
+            .collect();
+        let claims: Vec<doctor::Claims> = argvs
+            .iter()
+            .map(|(name, argv)| {
+                let definition = &stack[name.as_str()].0.definition;
+                doctor::Claims {
+                    name,
+                    argv: argv.as_deref(),
+                    pidfile: definition.value("PIDFILE"),
+                    socket: definition.value("SOCKET"),
+                }
+            })
+            .collect();
+        doctor::collisions(&claims)
     }
 
-    fn stop(&self, name: &str) -> String {
-        match self.stack.get(name) {
-            Some(service) => service.stop().to_string(),
-            None => String::from("service: can't find {name}"),
+    fn doctor(&self) -> String {
+        let stack = self.services();
+        let pids: Vec<(&String, u32)> = stack
+            .iter()
+            .map(|(name, service)| (name, service.0.pid.load(Ordering::Acquire)))
+            .filter(|(_, pid)| *pid != 0)
+            .collect();
+        let entries: Vec<doctor::Entry> = pids
+            .iter()
+            .map(|(name, pid)| doctor::Entry {
+                name,
+                command: &stack[name.as_str()].0.definition.exec,
+                pid: *pid,
+            })
+            .collect();
+
+        let proc = doctor::RealProc;
+        let findings: Vec<String> = [
+            doctor::missing_pids(&proc, &entries),
+            doctor::zombies(&proc, &entries),
+            doctor::fd_exhaustion(&proc, &entries),
+            doctor::cmdline_mismatches(&proc, &entries),
+            doctor::duplicate_pids(&entries),
+            doctor::socket(&paths().socket),
+            match LOG_OUTPUT {
+                "syslog" => Vec::new(),
+                _ => doctor::writable("log", &paths().log),
+            },
+            doctor::readable("config", &paths().config),
+            doctor::untouched(repair::untouched()),
+            self.collisions(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|finding| finding.to_string())
+        .collect();
+
+        match findings.is_empty() {
+            true => String::from("ok"),
+            false => findings.join("\n"),
         }
     }
 
-    fn restart(&self, name: &str) -> String {
-        match self.stack.get(name) {
-            Some(service) => service.stop().start().to_string(),
-            None => String::from("service: can't find {name}"),
+    fn sweep(&self) {
+        for (name, service) in self.entries() {
+            service.sweep(&name);
         }
     }
 
-    fn status(&self, name: &str) -> String {
-        match self.stack.get(name) {
-            Some(service) => service.to_string(),
-            None => String::from("service: can't find {name}"),
+    /// `run#name?exec=path&arg=a&keep`: a oneshot that is not in the config,
+    /// dropped TRANSIENT_RETENTION_SEC after it finished unless kept. A
+    /// finished transient of the same name is replaced.
+    fn run(&self, name: &str, options: &str) -> String {
+        if let Err(e) = definition::check_name(name) {
+            return format!("run: {}", e);
+        }
+        let Some(exec) = options_all(options, "exec").next() else {
+            return String::from("run: no executable");
+        };
+        let mut stack = self.stack.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(service) = stack.get(name) {
+            if service.0.transient.lock().unwrap().is_none() || service.running() {
+                return format!("run: {} already exists", name);
+            }
+        }
+
+        let service = ArcService::new(ServiceDefinition {
+            name: name.to_string(),
+            exec: PathBuf::from(exec),
+            args: options_all(options, "arg").collect(),
+            directives: vec![(String::from("Type"), String::from("oneshot"))],
+        });
+        *service.0.transient.lock().unwrap() = Some(option(options, "keep").is_some());
+        // stopped first on shutdown
+        service.0.order.store(usize::MAX, Ordering::Relaxed);
+        service.start_as(name, Origin::Manual);
+        let response = format!("{} {}", service, name);
+        stack.insert(name.to_string(), service);
+        response
+    }
+
+    /// Drops transients that finished more than TRANSIENT_RETENTION_SEC ago,
+    /// leaving their outcome in the event history.
+    fn expire_transients(&self) {
+        let retention = Duration::from_secs(TRANSIENT_RETENTION_SEC);
+        let mut stack = self.stack.write().unwrap_or_else(PoisonError::into_inner);
+        let expired: Vec<String> = stack
+            .iter()
+            .filter(|(_, service)| *service.0.transient.lock().unwrap() == Some(false))
+            .filter(|(_, service)| !service.running())
+            .filter(|(_, service)| service.0.status().since.elapsed() > retention)
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in expired {
+            if let Some(service) = stack.remove(&name) {
+                let exit = match *service.0.exit.lock().unwrap() {
+                    Some(exit) => exit.to_string(),
+                    None => String::from("never exited"),
+                };
+                info!("service: {}: transient expired", name);
+                events::record(format!(
+                    "removed transient {}: {}, {}",
+                    name,
+                    service.activity(),
+                    exit
+                ));
+            }
+        }
+    }
+
+    /// Stops every running service not listed in `keep`, as one grouped event.
+    fn stop_all_except(&self, keep: &str) -> String {
+        let entries: HashMap<String, ArcService> = self.entries().into_iter().collect();
+        let keep: Vec<&str> = keep.split(',').filter(|name| !name.is_empty()).collect();
+        let mut lines = Vec::new();
+        let mut stopped = Vec::new();
+
+        // dependents first, as at shutdown
+        let order = ServiceStack::stop_groups(&entries);
+        for (name, service) in order
+            .iter()
+            .flatten()
+            .filter_map(|name| entries.get_key_value(name))
+        {
+            if keep.contains(&name.as_str()) || !service.running() {
+                continue;
+            }
+            service.0.blame.lock().unwrap().stale = true;
+            lines.push(format!("{} {}", service.stop(), name));
+            stopped.push(name.as_str());
+        }
+
+        lines.push(format!("kept: {}", keep.join(", ")));
+        for name in keep.iter().filter(|name| !entries.contains_key(**name)) {
+            warn!("service: stop-all-except: unknown {}", name);
+            lines.push(format!("warn: unknown {}", name));
+        }
+
+        let summary = format!(
+            "stop-all-except: stopped {}; kept {}",
+            stopped.join(", "),
+            keep.join(", ")
+        );
+        info!("service: {}", summary);
+        events::record(summary);
+
+        lines.join("\n")
+    }
+
+    /// Re-reads the config: new services are autostarted, removed ones are
+    /// stopped and dropped unless they were started by hand.
+    fn reload(&self, fpath: &str) -> String {
+        let fresh = match ServiceStack::load(fpath) {
+            Ok(fresh) => fresh,
+            Err(e) => {
+                error!("{}", e);
+                return e;
+            }
+        };
+
+        // decided under the read lock, stopped with no lock, and only the
+        // map changes under the write lock, so status answers meanwhile
+        let mut lines = Vec::new();
+        let mut removed = Vec::new();
+        let mut added: Vec<(String, ArcService)>;
+        {
+            let stack = self.services();
+            let mut gone: Vec<(&String, &ArcService)> = stack
+                .iter()
+                .filter(|(_, service)| service.0.transient.lock().unwrap().is_none())
+                .filter(|(name, _)| !fresh.contains_key(*name))
+                .collect();
+            gone.sort_by(|a, b| a.0.cmp(b.0));
+            for (name, service) in gone {
+                if service.running() && service.origin() == Origin::Manual {
+                    lines.push(format!("kept {} ({})", name, Origin::Manual));
+                    continue;
+                }
+                removed.push((name.clone(), service.clone()));
+            }
+
+            for (name, service) in stack.iter() {
+                if let Some(fresh) = fresh.get(name) {
+                    let order = fresh.0.order.load(Ordering::Relaxed);
+                    service.0.order.store(order, Ordering::Relaxed);
+                }
+            }
+
+            added = fresh
+                .into_iter()
+                .filter(|(name, _)| !stack.contains_key(name))
+                .collect();
+            added.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+
+        for (_, service) in &removed {
+            service.stop();
+        }
+
+        let mut inserted = Vec::new();
+        {
+            let mut stack = self.stack.write().unwrap_or_else(PoisonError::into_inner);
+            for (name, service) in removed {
+                if stack
+                    .get(&name)
+                    .is_some_and(|current| Arc::ptr_eq(&current.0, &service.0))
+                {
+                    stack.remove(&name);
+                    lines.push(format!("removed {}", name));
+                }
+            }
+            for (name, service) in added {
+                if stack.contains_key(&name) {
+                    continue;
+                }
+                stack.insert(name.clone(), service.clone());
+                inserted.push((name, service));
+            }
+        }
+
+        let enabled = autostart::read();
+        for (name, service) in inserted {
+            if enabled
+                .as_ref()
+                .is_none_or(|enabled| enabled.contains(&name))
+            {
+                service.start_as(&name, Origin::Autostart);
+            }
+            lines.push(format!("added {}", name));
+        }
+
+        let summary = format!("reload: {}", lines.join(", "));
+        info!("config: {}", summary);
+        events::record(summary);
+
+        match lines.is_empty() {
+            true => String::from("reload: unchanged"),
+            false => lines.join("\n"),
         }
     }
 
-    fn start_all(&self) -> String {
-        let _: Vec<&ArcService> = self.stack.values().map(|s| s.start()).collect();
-        self.to_string()
+    /// The services of `stack` in groups to stop one after the other: each
+    /// service comes after everything that `Requires=` it, directly or
+    /// through others, and the services of one group don't depend on each
+    /// other. A cycle is cut where it closes.
+    fn stop_groups(stack: &HashMap<String, ArcService>) -> Vec<Vec<String>> {
+        fn depth<'a>(
+            name: &'a str,
+            dependents: &HashMap<&'a str, Vec<&'a str>>,
+            depths: &mut HashMap<&'a str, usize>,
+            path: &mut Vec<&'a str>,
+        ) -> usize {
+            if let Some(depth) = depths.get(name) {
+                return *depth;
+            }
+            path.push(name);
+            let mut deepest = 0;
+            for &dependent in dependents.get(name).into_iter().flatten() {
+                if !path.contains(&dependent) {
+                    deepest = deepest.max(1 + depth(dependent, dependents, depths, path));
+                }
+            }
+            path.pop();
+            depths.insert(name, deepest);
+            deepest
+        }
+
+        let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+        for (name, service) in stack {
+            for required in service.0.definition.requires() {
+                dependents.entry(required).or_default().push(name);
+            }
+        }
+        let mut depths = HashMap::new();
+        let mut groups: Vec<Vec<String>> = Vec::new();
+        for name in stack.keys() {
+            let depth = depth(name, &dependents, &mut depths, &mut Vec::new());
+            if groups.len() <= depth {
+                groups.resize(depth + 1, Vec::new());
+            }
+            groups[depth].push(name.clone());
+        }
+        for group in &mut groups {
+            group.sort();
+        }
+        groups
     }
 
+    /// Stops every running service, dependents before what they require:
+    /// group by group as `stop_groups` orders them, the services of a group
+    /// in parallel. The whole run is bounded by SHUTDOWN_TIMEOUT_SEC: each
+    /// group gets an even share of what is left, each service at most
+    /// SHUTDOWN_STOP_TIMEOUT_SEC of it, so the last group still gets its
+    /// SIGTERM before the budget runs out. Anything still up after that
+    /// gets SIGKILL.
     fn stop_all(&self) -> String {
-        let _: Vec<&ArcService> = self.stack.values().map(|s| s.stop()).collect();
-        self.to_string()
+        let entries: HashMap<String, ArcService> = self.entries().into_iter().collect();
+        let groups: Vec<Vec<(&str, &ArcService)>> = ServiceStack::stop_groups(&entries)
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .filter_map(|name| entries.get_key_value(name))
+                    .filter(|(_, service)| service.running())
+                    .map(|(name, service)| (name.as_str(), service))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|group| !group.is_empty())
+            .collect();
+
+        let deadline = Instant::now() + Duration::from_secs(SHUTDOWN_TIMEOUT_SEC);
+        let mut killed = Vec::new();
+        let mut truncated = Vec::new();
+        for (done, group) in groups.iter().enumerate() {
+            let left = (groups.len() - done) as u32;
+            let share = clock::share(deadline, Instant::now(), left);
+            let escalated: Vec<bool> = thread::scope(|scope| {
+                let stops: Vec<_> = group
+                    .iter()
+                    .map(|(name, service)| {
+                        let timeout = service.0.definition.stop_timeout();
+                        let budget = timeout
+                            .min(share)
+                            .min(Duration::from_secs(SHUTDOWN_STOP_TIMEOUT_SEC));
+                        if budget < timeout {
+                            truncated.push(format!(
+                                "{} ({}s -> {:.1}s)",
+                                name,
+                                timeout.as_secs(),
+                                budget.as_secs_f64()
+                            ));
+                        }
+                        let until = Instant::now() + budget;
+                        scope.spawn(move || {
+                            service.stop_by(until, service.0.definition.stop_signal())
+                        })
+                    })
+                    .collect();
+                stops
+                    .into_iter()
+                    .map(|stop| stop.join().unwrap_or(false))
+                    .collect()
+            });
+            killed.extend(
+                group
+                    .iter()
+                    .zip(escalated)
+                    .filter(|(_, escalated)| *escalated)
+                    .map(|((name, _), _)| *name),
+            );
+        }
+        for (name, service) in groups.iter().flatten() {
+            let pid = service.0.pid.swap(0, Ordering::AcqRel);
+            if pid != 0 {
+                kill_(pid, 9);
+                if !killed.contains(name) {
+                    killed.push(name);
+                }
+            }
+        }
+
+        let order: Vec<String> = groups
+            .iter()
+            .map(|group| {
+                let names: Vec<&str> = group.iter().map(|(name, _)| *name).collect();
+                names.join(", ")
+            })
+            .collect();
+        let order = order.join(" → ");
+        info!("service: stop-all: order {}", order);
+
+        let mut lines = vec![format!("order: {}", order)];
+        if !killed.is_empty() {
+            warn!("service: stop-all: killed {}", killed.join(", "));
+            lines.push(format!("killed: {}", killed.join(", ")));
+        }
+        if !truncated.is_empty() {
+            info!("service: stop-all: truncated {}", truncated.join(", "));
+            lines.push(format!("truncated: {}", truncated.join(", ")));
+        }
+        lines.push(self.to_string());
+        lines.join("\n")
     }
 }
 
-struct Service {
-    command: String,
+/// Bounds how many autostarted services may be launching at once.
+struct Slots {
+    free: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Slots {
+    fn new(count: usize) -> Arc<Self> {
+        Arc::new(Self {
+            free: Mutex::new(count),
+            freed: Condvar::new(),
+        })
+    }
+
+    fn acquire(self: &Arc<Self>) -> Slot {
+        let mut free = self.free.lock().unwrap();
+        while *free == 0 {
+            free = self.freed.wait(free).unwrap();
+        }
+        *free -= 1;
+        Slot(Arc::clone(self))
+    }
+}
+
+/// Returned to its Slots when dropped.
+struct Slot(Arc<Slots>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Time from autostart begin to the first successful spawn.
+#[derive(Default)]
+struct Blame {
+    begin: Option<Instant>,
+    took: Option<Duration>,
+    stale: bool,
+}
+
+#[derive(Clone, PartialEq)]
+enum State {
+    Stopped,
+    Running,
+    /// Crashed quickly, respawning at `next`.
+    Retrying {
+        next: Instant,
+        attempt: u32,
+    },
+    /// Not spawnable until the condition in the reason clears up.
+    Waiting(String),
+    /// `stop` signalled the process and waits for it; `grace` while the
+    /// PRESTOP_SIGNAL grace runs.
+    Stopping {
+        grace: bool,
+    },
+    /// Only entered through `Service::fail`.
+    Failed(Failure),
+}
+
+impl State {
+    /// Why the service is waiting or failed.
+    fn reason(&self) -> Option<&str> {
+        match self {
+            State::Waiting(reason) => Some(reason),
+            State::Failed(failure) => Some(failure.reason()),
+            _ => None,
+        }
+    }
+}
+
+struct Status {
+    state: State,
+    /// When `state` was entered.
+    since: Instant,
+    /// Last sign of life from the supervisor thread.
+    heartbeat: Instant,
+}
+
+/// Extra environment and trailing arguments given to one `start`.
+#[derive(Clone, Default)]
+struct Overrides {
+    env: Vec<(String, String)>,
     args: Vec<String>,
+}
+
+impl Overrides {
+    /// From the `env=K=V` and `arg=...` options of a start request.
+    fn parse(options: &str) -> Self {
+        Self {
+            env: options_all(options, "env")
+                .filter_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    Some((key.to_string(), value.to_string()))
+                })
+                .collect(),
+            args: options_all(options, "arg").collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.env.is_empty() && self.args.is_empty()
+    }
+}
+
+impl Display for Overrides {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let env = self
+            .env
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value));
+        let args = self.args.iter().map(|arg| format!("+{}", arg));
+        write!(f, "{}", env.chain(args).collect::<Vec<String>>().join(" "))
+    }
+}
+
+/// The argv after PATH resolution and the names (not values) of the
+/// environment it got, each capped at EXECUTED_MAX bytes.
+struct Executed {
+    argv: String,
+    env: String,
+}
+
+impl Executed {
+    fn new(definition: &ServiceDefinition, overrides: &Overrides) -> Self {
+        let exec = mounts::resolve(&definition.exec).unwrap_or_else(|| definition.exec.clone());
+        let argv = std::iter::once(exec.display().to_string())
+            .chain(definition.args.iter().cloned())
+            .chain(overrides.args.iter().cloned())
+            .collect::<Vec<String>>()
+            .join(" ");
+        let argv = secrets::mask(&argv, &Service::secrets_of(definition, overrides));
+        let mut env: Vec<String> = std::env::vars_os()
+            .map(|(key, _)| key.to_string_lossy().into_owned())
+            .chain(definition.env().into_iter().map(|(key, _)| key))
+            .chain(overrides.env.iter().map(|(key, _)| key.clone()))
+            .collect();
+        env.sort();
+        env.dedup();
+
+        Self {
+            argv: Executed::cap(argv),
+            env: Executed::cap(env.join(" ")),
+        }
+    }
+
+    fn cap(line: String) -> String {
+        memory::cap(line, EXECUTED_MAX)
+    }
+
+    fn bytes(&self) -> usize {
+        self.argv.capacity() + self.env.capacity()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Origin {
+    Autostart,
+    Manual,
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Autostart => write!(f, "autostart"),
+            Origin::Manual => write!(f, "manual"),
+        }
+    }
+}
+
+struct Service {
+    definition: ServiceDefinition,
     allow_run: AtomicBool,
     pid: AtomicU32,
     guardian: Mutex<Option<JoinHandle<()>>>,
+    /// Bumped by every start; supervisors of older generations bow out.
+    generation: AtomicU64,
+    blame: Mutex<Blame>,
+    status: Mutex<Status>,
+    /// Output lines dropped by the rate limiter since the last spawn.
+    dropped: AtomicU64,
+    /// Mount point backing the executable at the last successful spawn.
+    mount: Mutex<Option<String>>,
+    /// What the last successful spawn actually ran.
+    executed: Mutex<Option<Executed>>,
+    /// Optional behaviour of the running incarnation, see `features()`.
+    features: Mutex<Vec<&'static str>>,
+    /// Executable mtime and size at the last successful spawn.
+    stamp: Mutex<Option<(SystemTime, u64)>>,
+    /// Position in the config file; stop-all goes in reverse.
+    order: AtomicUsize,
+    /// Given to the `start` of the current incarnation.
+    overrides: Mutex<Overrides>,
+    /// Who started the current incarnation.
+    origin: Mutex<Origin>,
+    /// Autostart slot, released on the first state change after launch.
+    slot: Mutex<Option<Slot>>,
+    /// How the last spawn ended, None while it runs.
+    exit: Mutex<Option<ExitStatus>>,
+    /// Successful spawns since the daemon started.
+    spawns: AtomicU64,
+    /// Some for a `run` service not in the config, true if pinned with `keep`.
+    transient: Mutex<Option<bool>>,
 }
 
 impl Service {
-    fn new(command: String, args: Vec<String>) -> Self {
+    fn new(definition: ServiceDefinition) -> Self {
         Self {
-            command,
-            args,
-            allow_run: AtomicBool::new(true),
+            definition,
+            allow_run: AtomicBool::new(false),
             pid: AtomicU32::new(0),
             guardian: Mutex::new(None),
+            generation: AtomicU64::new(0),
+            blame: Mutex::new(Blame::default()),
+            status: Mutex::new(Status {
+                state: State::Stopped,
+                since: Instant::now(),
+                heartbeat: Instant::now(),
+            }),
+            dropped: AtomicU64::new(0),
+            mount: Mutex::new(None),
+            executed: Mutex::new(None),
+            features: Mutex::new(Vec::new()),
+            stamp: Mutex::new(None),
+            order: AtomicUsize::new(0),
+            overrides: Mutex::new(Overrides::default()),
+            origin: Mutex::new(Origin::Autostart),
+            slot: Mutex::new(None),
+            exit: Mutex::new(None),
+            spawns: AtomicU64::new(0),
+            transient: Mutex::new(None),
+        }
+    }
+
+    /// Status stays readable even if a supervisor panicked while holding it.
+    fn status(&self) -> MutexGuard<'_, Status> {
+        self.status.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn beat(&self) {
+        self.status().heartbeat = Instant::now();
+    }
+
+    /// Whether the supervisor of `generation` should keep the service up;
+    /// a stop or a newer start disowns it.
+    fn allowed(&self, generation: u64) -> bool {
+        self.allow_run.load(Ordering::Acquire)
+            && self.generation.load(Ordering::Acquire) == generation
+    }
+
+    /// Values to mask in anything printed about this service.
+    fn secrets(&self) -> Vec<String> {
+        Service::secrets_of(&self.definition, &self.overrides.lock().unwrap())
+    }
+
+    fn secrets_of(definition: &ServiceDefinition, overrides: &Overrides) -> Vec<String> {
+        let listed = definition.secret_env();
+        let mut values = definition.secrets();
+        values.extend(
+            overrides
+                .env
+                .iter()
+                .filter(|(key, _)| secrets::secret(key, &listed))
+                .map(|(_, value)| value.clone()),
+        );
+        values
+    }
+
+    /// Fires hook `which` if the definition has one, with the env of the
+    /// current incarnation.
+    fn hook(&self, which: &'static str) {
+        if let Some(command) = self.definition.value(which) {
+            let mut env = self.definition.env();
+            env.extend(self.overrides.lock().unwrap().env.clone());
+            let shown = secrets::mask(command, &self.secrets());
+            hooks::fire(&self.definition.name, which, command, &shown, env);
+        }
+    }
+
+    /// `netns` is the open `NETNS=` namespace, if any.
+    fn spawn(&self, netns: Option<i32>) -> std::io::Result<Child> {
+        let overrides = self.overrides.lock().unwrap().clone();
+        let mut command = Command::new(&self.definition.exec);
+        command
+            .args(&self.definition.args)
+            .args(&overrides.args)
+            .envs(self.definition.env())
+            .envs(overrides.env)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(dir) = self.definition.working_dir() {
+            command.current_dir(dir);
+        }
+        if self.definition.value("Handles").is_some() {
+            command.env(EXT_SOCKET_ENV, extension::socket(&self.definition.name));
+        }
+
+        let inherited = inherited_fds();
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(fd) = netns {
+                    netns::enter(fd)?;
+                }
+                reset_child(&inherited);
+                Ok(())
+            })
         }
+        .spawn()
+    }
+
+    /// Supervisor thread body: spawns the command and respawns it as long as
+    /// `generation` is allowed to run.
+    fn supervise(self: Arc<Self>, generation: u64) {
+        let mut attempt = 0;
+        let mut spawns = 0;
+
+        let runtime_dir = self.definition.runtime_dir();
+        if let Err(e) = stats::track_write(std::fs::create_dir_all(&runtime_dir)) {
+            warn!("service: bad create {}: {}", runtime_dir.display(), e);
+        }
+
+        loop {
+            let spawn = self.spawns.load(Ordering::Relaxed) + 1;
+            let log = stats::track_write(output::open(&self.definition.name, spawn))
+                .map_err(|e| {
+                    let path = output::path(&self.definition.name).unwrap_or_default();
+                    error!(
+                        "service: bad open {}: {}, output dropped",
+                        path.display(),
+                        e
+                    )
+                })
+                .ok();
+            let namespace = match self.definition.netns().map(netns::open).transpose() {
+                Ok(namespace) => namespace,
+                Err(e) => {
+                    error!("command: bad start: {}: {}", self.definition, e);
+                    self.fail(Code::NetnsUnavailable, e);
+                    break;
+                }
+            };
+            let mut command = match self.spawn(namespace.as_ref().map(File::as_raw_fd)) {
+                Ok(command) => command,
+                Err(e) => {
+                    if let Some(point) = self.gone_mount(&e) {
+                        if self.wait_mount(&point, generation) {
+                            continue;
+                        }
+                        self.set_state(State::Stopped);
+                    } else {
+                        error!("command: bad start: {}: {}", self.definition, e);
+                        self.fail(Code::spawn(&e), format!("bad start: {}", e));
+                    }
+                    break;
+                }
+            };
+
+            self.pid.store(command.id(), Ordering::Release);
+            *self.exit.lock().unwrap() = None;
+            self.spawns.fetch_add(1, Ordering::Relaxed);
+            self.set_state(State::Running);
+            self.dropped.store(0, Ordering::Relaxed);
+            *self.stamp.lock().unwrap() = self.definition.stamp();
+            *self.features.lock().unwrap() = self.definition.features();
+            let executed = Executed::new(&self.definition, &self.overrides.lock().unwrap());
+            *self.executed.lock().unwrap() = Some(executed);
+            *self.mount.lock().unwrap() = mounts::resolve(&self.definition.exec)
+                .and_then(|path| mounts::backing_mount(&path));
+            if spawns == 0 || self.definition.onstart_every_spawn() {
+                self.hook("ONSTART");
+            }
+            spawns += 1;
+
+            let sink = |log: Option<&File>| -> Box<dyn Write + Send> {
+                match log.and_then(|log| log.try_clone().ok()) {
+                    Some(log) => Box::new(log),
+                    None => Box::new(std::io::sink()),
+                }
+            };
+            let limiter = Arc::new(Mutex::new(Limiter::new()));
+            if let Some(stdout) = command.stdout.take() {
+                let (service, limiter, sink) =
+                    (Arc::clone(&self), Arc::clone(&limiter), sink(log.as_ref()));
+                thread::spawn(move || pump(stdout, sink, &limiter, &service.dropped));
+            }
+            if let Some(stderr) = command.stderr.take() {
+                let (service, limiter, sink) =
+                    (Arc::clone(&self), Arc::clone(&limiter), sink(log.as_ref()));
+                thread::spawn(move || pump(stderr, sink, &limiter, &service.dropped));
+            }
+
+            let mut blame = self.blame.lock().unwrap();
+            if let Some(begin) = blame.begin.take() {
+                blame.took = Some(begin.elapsed());
+            }
+            drop(blame);
+
+            let start_time = Instant::now();
+
+            let exit = loop {
+                match command.try_wait() {
+                    Ok(Some(exit)) => break Ok(exit),
+                    Ok(None) => {
+                        self.beat();
+                        thread::sleep(Duration::from_millis(SUPERVISE_TICK_MS));
+                    }
+                    Err(e) => break Err(e),
+                }
+            };
+
+            self.pid.store(0, Ordering::Release);
+            let exit = match exit {
+                Ok(exit) => exit,
+                Err(e) => {
+                    // no way left to tell when it ends, so it must not outlive us
+                    let _ = command.kill();
+                    error!("command: bad wait: {}: {}", self.definition, e);
+                    self.fail(Code::SupervisorError, format!("bad wait: {}", e));
+                    break;
+                }
+            };
+            *self.exit.lock().unwrap() = Some(exit);
+
+            let respawn = match self.definition.restart() {
+                Restart::Always => true,
+                Restart::OnFailure => !exit.success(),
+                Restart::Never => false,
+            };
+            if !self.allowed(generation) {
+                self.set_state(State::Stopped);
+                break;
+            }
+            // an exit that isn't respawned is final, a failed one stays failed
+            if !respawn {
+                match exit.success() {
+                    true => self.set_state(State::Stopped),
+                    false => self.fail(Code::exit(exit), exit.to_string()),
+                }
+                break;
+            }
+            restarts::record(&self.definition.name);
+
+            // a run that lasted is respawned after RestartSec if set, else
+            // right away; quick crashes back off
+            let restart_sec = self.definition.restart_sec();
+            if start_time.elapsed() > Duration::from_secs(RESTART_SEC) {
+                attempt = 0;
+                if self.definition.value("RestartSec").is_some()
+                    && !self.retry(restart_sec, 0, generation)
+                {
+                    self.set_state(State::Stopped);
+                    break;
+                }
+                continue;
+            }
+
+            attempt += 1;
+            if attempt >= START_LIMIT {
+                self.fail(Code::StartLimit, format!("{}, start limit hit", exit));
+                break;
+            }
+            let backoff = clock::backoff(restart_sec, attempt);
+            if !self.retry(backoff, attempt, generation) {
+                self.set_state(State::Stopped);
+                break;
+            }
+        }
+
+        info!("command: terminate: {}", self.definition);
+        if spawns > 0 && matches!(self.status().state, State::Stopped) {
+            self.hook("ONSTOP");
+        }
+
+        let mut guardian = self.guardian.lock().unwrap_or_else(PoisonError::into_inner);
+        if self.generation.load(Ordering::Acquire) == generation {
+            *guardian = None;
+            self.allow_run.store(false, Ordering::Release);
+        }
+    }
+
+    /// Sits in Retrying for `backoff` seconds before respawn `attempt`;
+    /// false if stopped or cancelled meanwhile.
+    fn retry(&self, backoff: u64, attempt: u32, generation: u64) -> bool {
+        let next = Instant::now() + Duration::from_secs(backoff);
+        self.set_state(State::Retrying { next, attempt });
+
+        while Instant::now() < next {
+            if !self.allowed(generation) {
+                return false;
+            }
+            self.beat();
+            thread::sleep(Duration::from_millis(SUPERVISE_TICK_MS));
+        }
+
+        self.allowed(generation)
+    }
+
+    fn set_state(&self, state: State) {
+        let mut status = self.status();
+        status.state = state;
+        status.since = Instant::now();
+        status.heartbeat = Instant::now();
+        drop(status);
+
+        self.slot
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+    }
+
+    /// The one way into State::Failed, so every failure carries a code; also
+    /// recorded as an event.
+    fn fail(&self, code: Code, reason: String) {
+        let failure = Failure::new(code, reason);
+        info!("service: {}: failed: {}", self.definition.name, failure);
+        events::record(format!("{} failed: {}", self.definition.name, failure));
+        self.set_state(State::Failed(failure));
+    }
+
+    /// The recorded backing mount, if a failed spawn is explained by it being gone.
+    fn gone_mount(&self, e: &std::io::Error) -> Option<String> {
+        match (e.kind(), self.mount.lock().unwrap().clone()) {
+            (ErrorKind::NotFound, Some(point)) if !mounts::is_mounted(&point) => Some(point),
+            _ => None,
+        }
+    }
+
+    /// Holds respawns until `point` is mounted again; false if stopped meanwhile.
+    fn wait_mount(&self, point: &str, generation: u64) -> bool {
+        warn!(
+            "command: {}: backing mount {} is gone",
+            self.definition.exec.display(),
+            point
+        );
+        self.set_state(State::Waiting(format!("backing mount {} is gone", point)));
+
+        while self.allowed(generation) && !mounts::is_mounted(point) {
+            thread::sleep(Duration::from_secs(MOUNT_RECHECK_SEC));
+            self.beat();
+        }
+
+        self.allowed(generation)
     }
 }
 
+#[derive(Clone)]
 struct ArcService(Arc<Service>);
 impl Display for ArcService {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -155,151 +1849,660 @@ impl Display for ArcService {
 }
 
 impl ArcService {
-    fn new(command: String, args: Vec<String>) -> Self {
-        Self(Arc::new(Service::new(command, args)))
+    fn new(definition: ServiceDefinition) -> Self {
+        Self(Arc::new(Service::new(definition)))
+    }
+
+    fn origin(&self) -> Origin {
+        *self.0.origin.lock().unwrap()
+    }
+
+    /// Starts the service from its on-disk definition if it isn't supervised
+    /// yet, recording who asked.
+    fn start_as(&self, name: &str, origin: Origin) -> &Self {
+        self.start_with(name, origin, Overrides::default())
+    }
+
+    /// `start_as` with `overrides` for this incarnation; they are kept across
+    /// its respawns and dropped by the next start.
+    fn start_with(&self, name: &str, origin: Origin, overrides: Overrides) -> &Self {
+        if !self.running() {
+            if !overrides.is_empty() {
+                let secrets = Service::secrets_of(&self.0.definition, &overrides);
+                let shown = secrets::mask(&overrides.to_string(), &secrets);
+                info!("service: {}: overrides {}", name, shown);
+            }
+            *self.0.overrides.lock().unwrap() = overrides;
+            *self.0.origin.lock().unwrap() = origin;
+            events::record(format!("start {} ({})", name, origin));
+        } else {
+            self.0.slot.lock().unwrap().take();
+        }
+        self.start()
+    }
+
+    /// What changed on disk since the last spawn, given the service's current
+    /// config line; nothing if it was dropped from the config.
+    fn change(&self, fresh: Option<&ServiceDefinition>) -> Option<&'static str> {
+        let fresh = fresh?;
+        if *fresh != self.0.definition {
+            return Some("definition changed");
+        }
+        match *self.0.stamp.lock().unwrap() {
+            Some(stamp) if Some(stamp) != fresh.stamp() => Some("binary changed"),
+            _ => None,
+        }
+    }
+
+    /// Approximate bytes of strings held for this service: definition, last
+    /// argv and environment, overrides and state reason.
+    fn bytes(&self) -> usize {
+        let definition = &self.0.definition;
+        let strings = |strings: &[String]| strings.iter().map(String::capacity).sum::<usize>();
+        let reason = self.0.status().state.reason().map_or(0, str::len);
+        let overrides = self.0.overrides.lock().unwrap();
+        definition.name.capacity()
+            + definition.exec.capacity()
+            + strings(&definition.args)
+            + definition
+                .directives
+                .iter()
+                .map(|(key, value)| key.capacity() + value.capacity())
+                .sum::<usize>()
+            + self
+                .0
+                .executed
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map_or(0, Executed::bytes)
+            + strings(&overrides.args)
+            + overrides
+                .env
+                .iter()
+                .map(|(key, value)| key.capacity() + value.capacity())
+                .sum::<usize>()
+            + reason
+    }
+
+    /// Features as spawned last, none before the first spawn.
+    fn features(&self) -> Vec<&'static str> {
+        self.0.features.lock().unwrap().clone()
+    }
+
+    /// `is-active` answer: Retrying and Waiting count as "activating", not active.
+    /// A oneshot is "running" until it "completed" or "failed".
+    fn activity(&self) -> &'static str {
+        let state = self.0.status().state.clone();
+        if self.0.definition.oneshot() {
+            let succeeded = self
+                .0
+                .exit
+                .lock()
+                .unwrap()
+                .is_some_and(|exit| exit.success());
+            match state {
+                State::Running => return "running",
+                State::Stopped if succeeded => return "completed",
+                _ => (),
+            }
+        }
+        match state {
+            State::Running => "active",
+            State::Retrying { .. } | State::Waiting(_) => "activating",
+            State::Stopping { .. } => "deactivating",
+            State::Failed(_) => "failed",
+            State::Stopped => "inactive",
+        }
+    }
+
+    fn running(&self) -> bool {
+        self.0
+            .guardian
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some()
+    }
+
+    /// Reason, dropped lines and the like, whatever there is to note.
+    fn notes(&self) -> Vec<String> {
+        let mut notes = Vec::new();
+        match &self.0.status().state {
+            State::Failed(failure) => notes.push(failure.to_string()),
+            State::Waiting(reason) => notes.push(reason.clone()),
+            State::Stopping { grace: true } => notes.push(String::from("stopping (grace)")),
+            State::Stopping { grace: false } => notes.push(String::from("stopping")),
+            State::Retrying { next, attempt: 0 } => notes.push(format!(
+                "restarting in {}s",
+                next.saturating_duration_since(Instant::now()).as_secs()
+            )),
+            State::Retrying { next, attempt } => notes.push(format!(
+                "retrying (next attempt in {}s, {}/{} attempts)",
+                next.saturating_duration_since(Instant::now()).as_secs(),
+                attempt,
+                START_LIMIT
+            )),
+            _ => (),
+        }
+        if !self.0.overrides.lock().unwrap().is_empty() {
+            notes.push(String::from("running with overrides"));
+        }
+        match *self.0.transient.lock().unwrap() {
+            Some(true) => notes.push(String::from("transient, kept")),
+            Some(false) => notes.push(String::from("transient")),
+            None => (),
+        }
+        let dropped = self.0.dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            notes.push(format!("dropped {} lines", dropped));
+        }
+        notes
+    }
+
+    /// Fails the service if its supervisor stopped heartbeating while
+    /// claiming to run it.
+    fn sweep(&self, name: &str) {
+        let status = self.0.status();
+        if status.state != State::Running
+            || status.heartbeat.elapsed() < Duration::from_secs(HEARTBEAT_SEC)
+        {
+            return;
+        }
+        drop(status);
+        self.0
+            .fail(Code::SupervisorError, String::from("supervisor died"));
+
+        error!("service: {}: supervisor died", name);
+        stats::SUPERVISOR_DEATHS.fetch_add(1, Ordering::Relaxed);
+
+        self.0.allow_run.store(false, Ordering::Release);
+        self.0.generation.fetch_add(1, Ordering::AcqRel);
+        let pid = self.0.pid.swap(0, Ordering::AcqRel);
+        if pid != 0 {
+            kill_(pid, 15);
+        }
+        *self
+            .0
+            .guardian
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
     }
 
     fn start(&self) -> &Self {
         let mut guardian = self.0.guardian.lock().unwrap();
 
         if guardian.is_none() {
+            // a waiter must not take the last incarnation's end for this one's
+            let mut status = self.0.status();
+            if matches!(status.state, State::Failed(_)) {
+                status.state = State::Stopped;
+                status.since = Instant::now();
+            }
+            drop(status);
+            *self.0.exit.lock().unwrap() = None;
+
             self.0.allow_run.store(true, Ordering::Relaxed);
+            let generation = self.0.generation.fetch_add(1, Ordering::AcqRel) + 1;
 
             let service = Arc::clone(&self.0);
 
-            *guardian = Some(thread::spawn(move || loop {
-                let mut command = match Command::new(&service.command).args(&service.args).spawn() {
-                    Ok(command) => command,
-                    Err(_) => {
-                        error!(
-                            "command: bad start: {} {}",
-                            &service.command,
-                            service.args.join(" ")
-                        );
-                        *service.guardian.lock().unwrap() = None;
-                        service.allow_run.store(false, Ordering::Release);
-                        break;
-                    }
-                };
-
-                service.pid.store(command.id(), Ordering::Release);
+            *guardian = Some(thread::spawn(move || service.supervise(generation)));
+        }
 
-                let start_time = Instant::now();
+        self
+    }
 
-                let success_exit = command.wait().unwrap().success();
+    /// Terminates the service and waits for its supervisor to wind down,
+    /// escalating to SIGKILL after the service's stop timeout.
+    fn stop(&self) -> &Self {
+        self.stop_with(self.0.definition.stop_signal())
+    }
 
-                service.pid.store(0, Ordering::Release);
+    /// `stop` asking the service to exit with `signal` instead of its
+    /// STOPSIGNAL.
+    fn stop_with(&self, signal: u32) -> &Self {
+        self.stop_by(Instant::now() + self.0.definition.stop_timeout(), signal);
+        self
+    }
 
-                let allow_restart = start_time.elapsed() > Duration::from_secs(RESTART_SEC);
-                let allow_run = service.allow_run.load(Ordering::Acquire);
+    /// `stop` with `signal`, escalating at `deadline`; true if SIGKILL was
+    /// needed.
+    fn stop_by(&self, deadline: Instant, signal: u32) -> bool {
+        let handle = self.0.guardian.lock().unwrap().take();
+        let mut killed = false;
+
+        if let Some(handle) = handle {
+            self.0.allow_run.store(false, Ordering::Release);
+
+            // pid 0 would signal our whole process group
+            let mut pid = self.0.pid.swap(0, Ordering::AcqRel);
+            let exited = || self.0.exit.lock().unwrap().is_some();
+            if pid != 0 {
+                if let Some((prestop, grace)) = self.0.definition.prestop() {
+                    self.0.set_state(State::Stopping { grace: true });
+                    info!(
+                        "command: {}: {}, {}s grace before {}",
+                        self.0.definition.exec.display(),
+                        signals::name(prestop),
+                        grace.as_secs(),
+                        signals::name(signal)
+                    );
+                    kill_(pid, prestop);
+                    let until = (Instant::now() + grace).min(deadline);
+                    while !exited() && Instant::now() < until {
+                        thread::sleep(Duration::from_millis(STOP_POLL_MS));
+                    }
+                }
+                if !exited() {
+                    self.0.set_state(State::Stopping { grace: false });
+                    kill_(pid, signal);
+                }
+            }
 
-                if !success_exit && allow_run && allow_restart {
-                    continue;
+            while !handle.is_finished() {
+                // a supervisor between spawn and storing the pid missed the swap
+                let late = self.0.pid.swap(0, Ordering::AcqRel);
+                if late != 0 {
+                    self.0.set_state(State::Stopping { grace: false });
+                    kill_(late, signal);
+                    pid = late;
+                }
+                if pid != 0 && !killed && Instant::now() >= deadline {
+                    warn!(
+                        "command: {}: no exit after {}, killing",
+                        self.0.definition.exec.display(),
+                        signals::name(signal)
+                    );
+                    kill_(pid, 9);
+                    killed = true;
                 }
+                thread::sleep(Duration::from_millis(STOP_POLL_MS));
+            }
+            // the supervisor may have settled the state before we set Stopping
+            if matches!(self.0.status().state, State::Stopping { .. }) {
+                self.0.set_state(State::Stopped);
+            }
 
-                info!(
-                    "command: terminate: {} {}",
-                    &service.command,
-                    service.args.join(" ")
-                );
-                *service.guardian.lock().unwrap() = None;
-                service.allow_run.store(false, Ordering::Release);
-                break;
-            }));
+            if !self.0.definition.keep_runtime_dir() {
+                let removed = std::fs::remove_dir_all(self.0.definition.runtime_dir());
+                let _ = stats::track_write(removed);
+            }
         }
 
-        self
+        killed
     }
+}
 
-    fn stop(&self) -> &Self {
-        let guardian = self.0.guardian.lock().unwrap();
+/// Removes a stale socket left at `path`, refusing to touch anything that is
+/// not a dead dctl socket.
+fn claim_socket(path: &str) -> Result<(), String> {
+    if path.len() > libc::SUN_PATH_MAX {
+        return Err(format!(
+            "socket: {} is {} bytes, a unix socket path takes at most {}",
+            path,
+            path.len(),
+            libc::SUN_PATH_MAX
+        ));
+    }
+    let parent = Path::new(path).parent().unwrap_or(Path::new("/"));
+    if !parent.is_dir() {
+        return Err(format!(
+            "socket: parent directory {} does not exist",
+            parent.display()
+        ));
+    }
 
-        if guardian.is_some() {
-            self.0.allow_run.store(false, Ordering::Relaxed);
+    let file_type = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata.file_type(),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("socket: bad stat {}: {}", path, e)),
+    };
 
-            kill_(self.0.pid.load(Ordering::Relaxed), 15);
+    if !file_type.is_socket() {
+        let kind = if file_type.is_dir() {
+            "directory"
+        } else if file_type.is_symlink() {
+            "symlink"
+        } else if file_type.is_file() {
+            "regular file"
+        } else {
+            "special file"
+        };
+        return Err(format!(
+            "socket: {} is a {}, refusing to remove it",
+            path, kind
+        ));
+    }
 
-            self.0.pid.store(0, Ordering::Release);
+    // only a refused connection proves nobody listens; any other error
+    // may hide a live owner
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            info!("socket: removing stale {}", path);
+            return std::fs::remove_file(path)
+                .map_err(|e| format!("socket: bad remove {}: {}", path, e));
         }
+        Err(e) => {
+            return Err(format!(
+                "socket: bad connect {}: {}, refusing to remove it",
+                path, e
+            ))
+        }
+    };
 
-        self
+    let mut response = String::new();
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+    let _ = stream.write_all(b"daemon#ping");
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+    let _ = stream.read_to_string(&mut response);
+
+    if response == format!("pong{}", END_MARKER) {
+        Err(format!("daemon: already running on {}", path))
+    } else {
+        Err(format!("socket: {} belongs to another program", path))
     }
 }
 
 fn daemon() {
-    let _ = SimpleLogger::init(LevelFilter::Info, LOG_PATH);
+    let output = match logger::Output::parse(LOG_OUTPUT) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let _ = SimpleLogger::init(LevelFilter::Info, &paths().log, output);
 
     info!("daemon: start running");
 
-    let _ = std::fs::remove_file(SOCKET_PATH);
-    let listener = UnixListener::bind(SOCKET_PATH).expect("socket: bad bind(path)");
+    if let Err(e) = claim_socket(&paths().socket) {
+        error!("{}", e);
+        eprintln!("{}", e);
+        log::logger().flush();
+        std::process::exit(1);
+    }
+    let listener = match UnixListener::bind(&paths().socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+            let e = format!("socket: bad bind {}: {}", &paths().socket, e);
+            error!("{}", e);
+            eprintln!("{}", e);
+            log::logger().flush();
+            std::process::exit(1);
+        }
+    };
 
     info!("service: start loading");
 
-    let stack = Arc::new(ServiceStack::init(CONFIG_PATH));
+    let stack = match ServiceStack::init(&paths().config) {
+        Ok(stack) => Arc::new(stack),
+        Err(e) => {
+            error!("{}", e);
+            eprintln!("{}", e);
+            let _ = std::fs::remove_file(&paths().socket);
+            log::logger().flush();
+            std::process::exit(1);
+        }
+    };
+
+    repair::reconcile(stack.services().keys());
 
-    let _ = stack.start_all();
+    let autostarter = Arc::clone(&stack);
+    thread::spawn(move || autostarter.autostart());
 
     info!("service: start running");
 
-    for stream in listener.incoming() {
-        let mut stream = stream.expect("socket: bad accept socket");
+    let sweeper = Arc::clone(&stack);
+    thread::spawn(move || {
+        let mut clock = clock::Watch::new();
+        loop {
+            thread::sleep(Duration::from_secs(SWEEP_SEC));
+            sweeper.sweep();
+            sweeper.expire_transients();
+            if let Some(step) = clock.step() {
+                warn!("clock: wall clock stepped by {:+}s", step);
+                events::record(format!("clock stepped by {:+}s", step));
+            }
+        }
+    });
+
+    let mut acceptor = accept::Acceptor::new(listener);
+    loop {
+        let mut stream = acceptor.accept();
 
         let stack = Arc::clone(&stack);
 
         thread::spawn(move || {
             let mut message = String::new();
-            stream
-                .read_to_string(&mut message)
-                .expect("message: bad read");
-            let message: Vec<&str> = message.split('#').collect();
+            if let Err(e) = stream.read_to_string(&mut message) {
+                return warn!("message: bad read: {}", e);
+            }
 
-            match (message[0], message[1]) {
+            let peer = peer_cred_(stream.as_raw_fd());
+            trace::request(peer, &message, |message| stack.mask(message));
+
+            let (verb, payload) = message.split_once('#').unwrap_or((&message, ""));
+            let (payload, options) = payload.split_once('?').unwrap_or((payload, ""));
+            let key = option(options, "key");
+
+            let turn = match verb {
+                "start" | "stop" | "restart" | "run" | "cancel-retry" => {
+                    match queue::enter(payload) {
+                        Ok(turn) => Some(turn),
+                        Err(e) => {
+                            warn!("{}", e);
+                            reply(&mut stream, &e);
+                            return;
+                        }
+                    }
+                }
+                _ => None,
+            };
+
+            match (verb, payload) {
                 ("daemon", "stop") => {
-                    stream
-                        .write_all(stack.stop_all().as_bytes())
-                        .expect("message: bad send");
+                    reply(&mut stream, &idem::once(key, || stack.stop_all()));
 
                     info!("daemon: daemon is ready to exit");
+                    log::logger().flush();
 
                     std::process::exit(0);
                 }
+                ("daemon", toggle @ ("trace:on" | "trace:off")) => {
+                    match peer {
+                        Some((_, 0)) if toggle == "trace:on" => trace::enable(),
+                        Some((_, 0)) => trace::disable(),
+                        _ => {
+                            error!("trace: permission denied");
+                            reply(&mut stream, "trace: permission denied");
+                            return;
+                        }
+                    }
+                    reply(&mut stream, toggle);
+                }
+                ("daemon", keep) if keep.starts_with("stop-all-except:") => {
+                    let keep = &keep["stop-all-except:".len()..];
+                    reply(
+                        &mut stream,
+                        &idem::once(key, || stack.stop_all_except(keep)),
+                    );
+                }
+                ("daemon", "events") => {
+                    reply(&mut stream, &events::render());
+                }
+                ("daemon", "doctor") => {
+                    reply(&mut stream, &stack.doctor());
+                }
+                ("daemon", "restart-changed") => {
+                    reply(
+                        &mut stream,
+                        &idem::once(key, || stack.restart_changed(&paths().config)),
+                    );
+                }
+                ("daemon", "reload") => {
+                    reply(
+                        &mut stream,
+                        &idem::once(key, || stack.reload(&paths().config)),
+                    );
+                }
+                ("daemon", "config") => {
+                    reply(&mut stream, &stack.config());
+                }
+                ("daemon", "info") => {
+                    reply(&mut stream, &stack.info());
+                }
+                ("daemon", "ping") => {
+                    reply(&mut stream, "pong");
+                }
+                ("daemon", "blame") => {
+                    reply(&mut stream, &stack.blame());
+                }
+                ("daemon", "collisions") => {
+                    let findings: Vec<String> =
+                        stack.collisions().iter().map(ToString::to_string).collect();
+                    reply(
+                        &mut stream,
+                        &match findings.is_empty() {
+                            true => String::from("ok"),
+                            false => findings.join("\n"),
+                        },
+                    );
+                }
+                ("daemon", "fdtop") => {
+                    let top = option(options, "top").and_then(|n| n.parse().ok());
+                    reply(&mut stream, &stack.fdtop(top.unwrap_or(FDTOP)));
+                }
+                ("daemon", "status") if option(options, "porcelain").is_some() => {
+                    reply(&mut stream, &stack.porcelain(option(options, "features")));
+                }
+                ("daemon", "status") if option(options, "json").is_some() => {
+                    reply(&mut stream, &stack.json(option(options, "features")));
+                }
                 ("daemon", "status") => {
-                    stream
-                        .write_all(stack.to_string().as_bytes())
-                        .expect("message: bad send");
+                    let errors = option(options, "errors").and_then(|n| n.parse().ok());
+                    let long = option(options, "long").is_some();
+                    let table = stack.render(option(options, "features"), long);
+                    let response = match errors {
+                        Some(n) => {
+                            let mut lines = vec![table, String::from("errors:")];
+                            lines.extend(logger::recent(n));
+                            lines.join("\n")
+                        }
+                        None => table,
+                    };
+                    reply(&mut stream, &response);
+                }
+                ("status", names) if names.contains(',') => {
+                    reply(
+                        &mut stream,
+                        &stack.status_many(names, option(options, "features")),
+                    );
+                }
+                ("status", name) if option(options, "full").is_some() => {
+                    reply(&mut stream, &stack.status_full(name));
                 }
                 ("status", name) => {
-                    stream
-                        .write_all(stack.status(name).as_bytes())
-                        .expect("message: bad send");
+                    reply(&mut stream, &stack.status(name));
+                }
+                (verb @ ("enable" | "disable"), names) => {
+                    let strict = option(options, "strict").is_some();
+                    let dry_run = option(options, "dry-run").is_some();
+                    let response = idem::once(key, || {
+                        stack.set_enabled(names, verb == "enable", strict, dry_run)
+                    });
+                    reply(&mut stream, &response);
+                }
+                ("show", name) => {
+                    let running = option(options, "running").is_some();
+                    reply(&mut stream, &stack.show(name, running));
+                }
+                ("why", name) => {
+                    reply(&mut stream, &stack.why(name));
+                }
+                ("deps", name) => {
+                    reply(&mut stream, &stack.deps(name));
+                }
+                ("log", name) => {
+                    let lines = option(options, "lines").and_then(|n| n.parse().ok());
+                    reply(&mut stream, &output::tail(name, lines.unwrap_or(LOG_TAIL)));
+                }
+                ("is-active", name) => {
+                    reply(&mut stream, &stack.is_active(name));
+                }
+                ("run", name) => {
+                    info!("service: run: {name}");
+
+                    reply(&mut stream, &idem::once(key, || stack.run(name, options)));
+                }
+                ("cancel-retry", name) => {
+                    reply(&mut stream, &idem::once(key, || stack.cancel_retry(name)));
+                }
+                ("wait", name) => {
+                    let fd = stream.as_raw_fd();
+                    if let Some(response) = stack.wait(name, || hung_up_(fd)) {
+                        reply(&mut stream, &response);
+                    }
+                }
+                ("start", name) if option(options, "wait").is_some() => {
+                    info!("service: start: {name} (wait)");
+
+                    let overrides = Overrides::parse(options);
+                    let started =
+                        idem::once(key, || format!("{} {name}", stack.start(name, overrides)));
+                    // waiting for the outcome reads, the next request may go
+                    drop(turn);
+                    let fd = stream.as_raw_fd();
+                    if let Some(outcome) = stack.wait(name, || hung_up_(fd)) {
+                        reply(&mut stream, &format!("{}\n{}", started, outcome));
+                    }
                 }
                 ("start", name) => {
                     info!("service: start: {name}");
 
-                    stream
-                        .write_all(format!("{} {name}", stack.start(name)).as_bytes())
-                        .expect("message: bad send");
+                    let overrides = Overrides::parse(options);
+                    let response =
+                        idem::once(key, || format!("{} {name}", stack.start(name, overrides)));
+                    reply(&mut stream, &response);
                 }
                 ("stop", name) => {
                     info!("service: stop: {name}");
 
-                    stream
-                        .write_all(format!("{} {name}", stack.stop(name)).as_bytes())
-                        .expect("message: bad send");
+                    let signal = option(options, "signal");
+                    match signal.map(|signal| (signal, signals::parse(signal))) {
+                        Some((signal, None)) => {
+                            error!("option: unknown signal {:?}", signal);
+                            reply(&mut stream, &format!("option: unknown signal {:?}", signal));
+                        }
+                        parsed => {
+                            let signal = parsed.and_then(|(_, number)| number);
+                            let response =
+                                idem::once(key, || format!("{} {name}", stack.stop(name, signal)));
+                            reply(&mut stream, &response);
+                        }
+                    }
+                }
+                ("restart", name) if option(options, "if-changed").is_some() => {
+                    let response =
+                        idem::once(key, || stack.restart_if_changed(&paths().config, name));
+                    reply(&mut stream, &response);
                 }
                 ("restart", name) => {
                     info!("service: restart: {name}");
 
-                    stream
-                        .write_all(format!("{} {name}", stack.restart(name)).as_bytes())
-                        .expect("message: bad send");
+                    let response = idem::once(key, || format!("{} {name}", stack.restart(name)));
+                    reply(&mut stream, &response);
                 }
+                (verb, _) if verb.starts_with("x-") => match stack.handler(verb) {
+                    Some(name) => reply(&mut stream, &extension::forward(&name, &message)),
+                    None => {
+                        error!("option: invalid parameter");
+                        reply(&mut stream, "option: invalid parameter");
+                    }
+                },
                 _ => {
                     error!("option: invalid parameter");
-                    stream
-                        .write_all("option: invalid parameter".as_bytes())
-                        .expect("message: bad send");
+                    reply(&mut stream, "option: invalid parameter");
                 }
             }
 
@@ -308,38 +2511,838 @@ fn daemon() {
     }
 }
 
-fn client(args: (&str, &str)) {
-    let mut stream = UnixStream::connect(SOCKET_PATH).expect("socket: bad connect(path)");
-    stream
-        .write_all(format!("{}#{}", args.0, args.1).as_bytes())
-        .expect("message: bad send");
-    stream.shutdown(std::net::Shutdown::Write).unwrap();
+/// Escapes `%` and `&` in an option value, so it may hold anything.
+fn encode(value: &str) -> String {
+    value.replace('%', "%25").replace('&', "%26")
+}
+
+/// Every value given for option `name`, decoded, in request order.
+fn options_all<'a>(options: &'a str, name: &'a str) -> impl Iterator<Item = String> + 'a {
+    options
+        .split('&')
+        .filter_map(move |pair| match pair.split_once('=') {
+            Some((k, v)) if k == name => Some(v.replace("%26", "&").replace("%25", "%")),
+            _ => None,
+        })
+}
+
+/// Value of `name` in a `k=v&k2=v2` option string; bare flags yield "".
+fn option<'a>(options: &'a str, name: &str) -> Option<&'a str> {
+    options
+        .split('&')
+        .find_map(|pair| match pair.split_once('=') {
+            Some((k, v)) if k == name => Some(v),
+            None if pair == name => Some(""),
+            _ => None,
+        })
+}
+
+/// Every response ends with END_MARKER so the client can tell a finished
+/// reply from a connection that broke mid-write.
+fn reply(stream: &mut UnixStream, response: &str) {
+    trace::response(response);
+    if let Err(e) = stream.write_all(format!("{}{}", response, END_MARKER).as_bytes()) {
+        // the client hung up or gave up waiting
+        warn!("message: bad send: {}", e);
+    }
+}
+
+/// Connects to the daemon; with `deadline`, ENOENT and ECONNREFUSED are
+/// retried with exponential backoff until it passes.
+fn connect(deadline: Option<Instant>) -> UnixStream {
+    let mut backoff = Duration::from_millis(RETRY_BACKOFF_MS);
+    loop {
+        let e = match UnixStream::connect(&paths().socket) {
+            Ok(stream) => return stream,
+            Err(e) => e,
+        };
+
+        let transient = matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused);
+        match deadline {
+            Some(deadline) if transient && Instant::now() + backoff < deadline => {
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(Duration::from_secs(1));
+            }
+            _ => {
+                eprintln!("socket: bad connect({}): {}", &paths().socket, e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Sends one request, None if the response came back truncated; Err only
+/// when the read timed out.
+fn exchange(stream: &mut UnixStream, message: &str) -> std::io::Result<Option<String>> {
+    if stream.write_all(message.as_bytes()).is_err()
+        || stream.shutdown(std::net::Shutdown::Write).is_err()
+    {
+        return Ok(None);
+    }
 
     let mut response = String::new();
-    stream
-        .read_to_string(&mut response)
-        .expect("reponse: bad read");
-    println!("{}", response);
+    match stream.read_to_string(&mut response) {
+        Ok(_) => Ok(response.strip_suffix(END_MARKER).map(String::from)),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Err(e),
+        Err(_) => Ok(None),
+    }
+}
+
+/// How long the client waits for a response unless `--timeout` says
+/// otherwise. Each is CLIENT_MARGIN_SEC longer than the daemon's own bound on
+/// the operation, so that bound normally answers first; a service's
+/// TIMEOUTSTOP, and the PRESTOP_GRACE inside it, never exceeds
+/// STOP_TIMEOUT_SEC. Bulk verbs stop one service after another and have no
+/// bound.
+fn default_timeout(args: (&str, &str)) -> Option<Duration> {
+    let payload = args.1.split('?').next().unwrap_or_default();
+    let secs = match (args.0, payload) {
+        ("daemon", "stop") => SHUTDOWN_TIMEOUT_SEC + CLIENT_MARGIN_SEC,
+        ("daemon", "reload" | "restart-changed") => return None,
+        ("daemon", payload) if payload.starts_with("stop-all-except:") => return None,
+        ("stop" | "restart" | "cancel-retry", _) => STOP_TIMEOUT_SEC + CLIENT_MARGIN_SEC,
+        ("wait", _) => return None,
+        ("start", _) if args.1.contains("?wait") => return None,
+        (verb, _) if verb.starts_with("x-") => EXT_TIMEOUT_SEC + CLIENT_MARGIN_SEC,
+        _ => CLIENT_TIMEOUT_SEC,
+    };
+    Some(Duration::from_secs(secs))
+}
+
+fn client(
+    args: (&str, &str),
+    retry: Option<Duration>,
+    timeout: Option<Duration>,
+    via: Option<&str>,
+) {
+    let response = request(args, retry, timeout, via);
+
+    match args {
+        ("daemon", "blame") => print_blame(&response),
+        ("daemon", "doctor") => {
+            println!("{}", response);
+            if response.lines().any(|line| line.starts_with("error:")) {
+                std::process::exit(1);
+            }
+        }
+        ("is-active", _) => {
+            println!("{}", response);
+            if !matches!(response.as_str(), "active" | "completed") {
+                std::process::exit(1);
+            }
+        }
+        ("wait", _) => {
+            println!("{}", response);
+            waited(&response);
+        }
+        ("start", _) if args.1.contains("?wait") => {
+            println!("{}", response);
+            waited(response.lines().last().unwrap_or_default());
+        }
+        _ => println!("{}", response),
+    }
+}
+
+/// Sends `args`, over the socket or through the `--via` command, and returns
+/// the response, exiting on a timeout or a truncated response.
+fn request(
+    args: (&str, &str),
+    retry: Option<Duration>,
+    timeout: Option<Duration>,
+    via: Option<&str>,
+) -> String {
+    let mut message = format!("{}#{}", args.0, args.1);
+
+    // a key makes resending a mutating request safe
+    let resend = retry.is_some()
+        && (!mutating(args) || {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_nanos());
+            let separator = if args.1.contains('?') { '&' } else { '?' };
+            message.push_str(&format!(
+                "{}key={:x}-{:x}",
+                separator,
+                std::process::id(),
+                nanos
+            ));
+            true
+        });
+
+    let timeout = timeout.or_else(|| default_timeout(args));
+    let deadline = retry.map(|total| Instant::now() + total);
+    loop {
+        let sent = Instant::now();
+        let exchanged = match via {
+            Some(command) => proxy::exchange(command, &message, timeout),
+            None => {
+                let mut stream = connect(deadline);
+                let _ = stream.set_read_timeout(timeout);
+                exchange(&mut stream, &message)
+            }
+        };
+        match exchanged {
+            Ok(Some(response)) => break response,
+            Ok(None) if resend && deadline.is_some_and(|deadline| Instant::now() < deadline) => {
+                thread::sleep(Duration::from_millis(RETRY_BACKOFF_MS));
+            }
+            Ok(None) => {
+                eprintln!("response: truncated — daemon may have crashed");
+                std::process::exit(3);
+            }
+            Err(_) => {
+                let verb = match args.0 {
+                    "daemon" => args.1.split('?').next().unwrap_or_default(),
+                    verb => verb,
+                };
+                eprintln!(
+                    "response: {} timed out after {:.1}s",
+                    verb,
+                    sent.elapsed().as_secs_f64()
+                );
+                if mutating(args) {
+                    eprintln!("warn: {} may still complete in the daemon", verb);
+                }
+                std::process::exit(4);
+            }
+        }
+    }
+}
+
+/// `gen-service NAME --exec=PATH [--arg=A] [--env=K=V] [--restart=POLICY]
+/// [--restart-sec=N] [--working-dir=DIR] [--description=TEXT]
+/// [--set=Key=value]`: writes the config line of NAME into CONFIG_PATH.
+/// `--dry-run` prints the line instead, `--force` replaces an existing one,
+/// `--enable` adds NAME to autostart and `--now` loads and starts it.
+fn gen_service(
+    name: &str,
+    flags: &[String],
+    retry: Option<Duration>,
+    timeout: Option<Duration>,
+    via: Option<&str>,
+) {
+    let fail = |e: String| -> ! {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    };
+    let flag = |name: &str| flags.iter().any(|f| f == name);
+    let Some(exec) = flags.iter().find_map(|f| f.strip_prefix("--exec=")) else {
+        fail(String::from("gen-service: --exec=PATH is required"));
+    };
+
+    let mut args = Vec::new();
+    let mut directives = Vec::new();
+    for (flag, value) in flags.iter().filter_map(|flag| flag.split_once('=')) {
+        match flag {
+            "--arg" => args.push(value),
+            "--env" => directives.push(("Env", value)),
+            "--restart" => directives.push(("Restart", value)),
+            "--restart-sec" => directives.push(("RestartSec", value)),
+            "--working-dir" => directives.push(("WorkingDir", value)),
+            "--description" => directives.push(("Description", value)),
+            "--set" => match value.split_once('=') {
+                Some(pair) => directives.push(pair),
+                None => fail(format!(
+                    "gen-service: --set needs Key=value, not {:?}",
+                    value
+                )),
+            },
+            _ => (),
+        }
+    }
+    let line = scaffold::line(name, exec, &args, &directives).unwrap_or_else(|e| fail(e));
+
+    if flag("--dry-run") {
+        return println!("{}", line);
+    }
+    if via.is_some() {
+        fail(String::from(
+            "gen-service: writes the local config, which --via doesn't reach",
+        ));
+    }
+    let replaced =
+        scaffold::write(&paths().config, name, &line, flag("--force")).unwrap_or_else(|e| fail(e));
+    match replaced {
+        true => println!("replaced {} in {}", name, &paths().config),
+        false => println!("added {} to {}", name, &paths().config),
+    }
+
+    // the daemon has to know the service before it can enable it
+    if flag("--enable") || flag("--now") {
+        println!("{}", request(("daemon", "reload"), retry, timeout, via));
+    }
+    if flag("--enable") {
+        println!("{}", request(("enable", name), retry, timeout, via));
+    }
+    if flag("--now") {
+        let response = match replaced {
+            true => request(
+                ("restart", &format!("{}?if-changed", name)),
+                retry,
+                timeout,
+                via,
+            ),
+            false => request(("start", name), retry, timeout, via),
+        };
+        println!("{}", response);
+    }
+}
+
+/// Requests that change daemon or service state. Everything else (`status`,
+/// `is-active`, `show`, `wait`, `deps`, `why`, `log`, `daemon#status`,
+/// `daemon#info`, `daemon#config`, `daemon#ping`, `daemon#blame`,
+/// `daemon#doctor`, `daemon#events`, `daemon#fdtop`, `daemon#collisions`)
+/// is read-only and safe to issue from hook scripts.
+fn mutating(args: (&str, &str)) -> bool {
+    let payload = args.1.split('?').next().unwrap_or_default();
+    !matches!(
+        (args.0, payload),
+        (
+            "status" | "is-active" | "show" | "wait" | "deps" | "why" | "log",
+            _
+        ) | (
+            "daemon",
+            "status"
+                | "info"
+                | "config"
+                | "ping"
+                | "blame"
+                | "doctor"
+                | "events"
+                | "fdtop"
+                | "collisions"
+        )
+    )
+}
+
+/// Exits unless a wait ended active or completed; a oneshot that exited
+/// with a code passes it on.
+fn waited(outcome: &str) {
+    if matches!(outcome, "active" | "completed") {
+        return;
+    }
+    let code = outcome
+        .strip_prefix("failed: exit status: ")
+        .and_then(|rest| rest.split(' ').next()?.parse().ok());
+    std::process::exit(code.unwrap_or(1));
+}
+
+/// Renders `daemon#config` as aligned columns, or as JSON with one setting
+/// per line so two devices diff cleanly. Unknown directives are warnings.
+fn print_config(response: &str, json: bool) {
+    let quote = status::quote;
+    let mut settings = Vec::new();
+    let mut unknown = Vec::new();
+    for line in response.lines() {
+        match line.strip_prefix("unknown: ") {
+            Some(rest) => unknown.push(rest.split_once(' ').unwrap_or((rest, ""))),
+            None => match line.split_once(' ') {
+                Some(setting) => settings.push(setting),
+                None => println!("{}", line),
+            },
+        }
+    }
+
+    if json {
+        let settings: Vec<String> = settings
+            .iter()
+            .map(|(name, value)| {
+                format!(
+                    "    {{\"name\": {}, \"value\": {}}}",
+                    quote(name),
+                    quote(value)
+                )
+            })
+            .collect();
+        let unknown: Vec<String> = unknown
+            .iter()
+            .map(|(service, key)| {
+                format!(
+                    "    {{\"service\": {}, \"key\": {}}}",
+                    quote(service),
+                    quote(key)
+                )
+            })
+            .collect();
+        let array = |items: Vec<String>| match items.is_empty() {
+            true => String::from("[]"),
+            false => format!("[\n{}\n  ]", items.join(",\n")),
+        };
+        println!("{{");
+        println!("  \"settings\": {},", array(settings));
+        println!("  \"unknown\": {}", array(unknown));
+        println!("}}");
+        return;
+    }
+
+    let width = settings
+        .iter()
+        .map(|(name, ..)| name.len())
+        .max()
+        .unwrap_or(0);
+    for (name, value) in &settings {
+        println!("{:<width$} {}", name, value, width = width);
+    }
+    for (service, key) in &unknown {
+        eprintln!("warn: config: {}: unknown directive {}", service, key);
+    }
+}
+
+/// Renders `daemon#blame` like systemd-analyze blame.
+fn print_blame(response: &str) {
+    let secs = |millis: &str| millis.parse::<f64>().unwrap_or(0.0) / 1000.0;
+    for line in response.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        match fields.as_slice() {
+            ["total", millis] => println!("autostart took {:.3}s", secs(millis)),
+            [name, millis] => println!("{:>9.3}s {}", secs(millis), name),
+            [name, millis, _] => println!("{:>9.3}s {} (stale)", secs(millis), name),
+            _ => println!("{}", line),
+        }
+    }
 }
 
 fn main() {
     /*
         解析命令参数
     */
-    let args: Vec<String> = std::env::args().collect();
+    let (flags, args): (Vec<String>, Vec<String>) =
+        std::env::args().partition(|arg| arg.starts_with("--"));
+
+    // every file of daemon and client under one directory, for tests and
+    // development away from the real services
+    if let Some(dir) = flags
+        .iter()
+        .find_map(|flag| flag.strip_prefix("--sandbox="))
+    {
+        match std::path::absolute(dir) {
+            Ok(dir) => config::sandbox(&dir.to_string_lossy()),
+            Err(e) => {
+                eprintln!("option: bad --sandbox {}: {}", dir, e);
+                std::process::exit(2);
+            }
+        }
+    }
 
+    let names;
     let normalized_args = match args.len() {
         1 => ("daemon", "start"),
         2 => ("daemon", args[1].as_str()),
+        3 if args[1] == "config" && args[2] == "effective" => ("daemon", "config"),
         3 => (args[1].as_str(), args[2].as_str()),
+        _ if args[1] == "run" => ("run", args[2].as_str()),
+        _ if matches!(args[1].as_str(), "status" | "enable" | "disable") => {
+            names = args[2..].join(",");
+            (args[1].as_str(), names.as_str())
+        }
         _ => {
             eprintln!("option: bad command format");
             std::process::exit(-1);
         }
     };
 
+    let reentrant = std::env::var_os(HOOK_ENV).is_some();
+    if reentrant && mutating(normalized_args) && !flags.iter().any(|f| f == "--allow-reentrant") {
+        eprintln!(
+            "option: {} from a hook can deadlock the daemon, pass --allow-reentrant",
+            normalized_args.0
+        );
+        std::process::exit(2);
+    }
+
+    let retry = flags.iter().find_map(|flag| match flag.as_str() {
+        "--retry" => Some(Duration::from_secs(RETRY_SEC)),
+        _ => flag
+            .strip_prefix("--retry=")
+            .and_then(|secs| secs.parse::<f64>().ok())
+            .map(Duration::from_secs_f64),
+    });
+    let timeout = flags.iter().find_map(|flag| {
+        flag.strip_prefix("--timeout=")
+            .and_then(|secs| secs.parse::<f64>().ok())
+            .map(Duration::from_secs_f64)
+    });
+    let via = flags.iter().find_map(|flag| flag.strip_prefix("--via="));
+
+    // flags that travel as request options
+    let flag = |name: &str| flags.iter().any(|f| f == name);
+    let value = |name: &str| {
+        flags
+            .iter()
+            .find_map(|f| f.strip_prefix(name)?.strip_prefix('='))
+    };
+    let mut options = Vec::new();
+    match normalized_args {
+        ("restart", _) if flag("--if-changed") => options.push(String::from("if-changed")),
+        ("status", names) => {
+            if flag("--full") {
+                options.push(String::from("full"));
+            }
+            if let (true, Some(feature)) = (names.contains(','), value("--features")) {
+                options.push(format!("features={}", feature));
+            }
+        }
+        ("start", _) => {
+            if flag("--wait") {
+                options.push(String::from("wait"));
+            }
+            for flag in &flags {
+                if let Some(pair) = flag.strip_prefix("--env=") {
+                    options.push(format!("env={}", encode(pair)));
+                }
+                if let Some(arg) = flag.strip_prefix("--arg=") {
+                    options.push(format!("arg={}", encode(arg)));
+                }
+            }
+        }
+        ("show", _) if flag("--running") => options.push(String::from("running")),
+        ("stop", _) => {
+            if let Some(signal) = value("--signal") {
+                options.push(format!("signal={}", encode(signal)));
+            }
+        }
+        ("log", _) => {
+            if let Some(n) = value("--lines") {
+                options.push(format!("lines={}", n));
+            }
+        }
+        ("run", _) => {
+            if let Some(exec) = args.get(3) {
+                options.push(format!("exec={}", encode(exec)));
+            }
+            for arg in args.iter().skip(4) {
+                options.push(format!("arg={}", encode(arg)));
+            }
+            if flag("--keep") {
+                options.push(String::from("keep"));
+            }
+        }
+        ("enable" | "disable", _) => {
+            if flag("--strict") {
+                options.push(String::from("strict"));
+            }
+            if flag("--dry-run") {
+                options.push(String::from("dry-run"));
+            }
+        }
+        ("daemon", "fdtop") => {
+            if let Some(n) = value("--top") {
+                options.push(format!("top={}", n));
+            }
+        }
+        ("daemon", "status") if value("--save").is_some() || value("--diff").is_some() => {
+            options.push(String::from("porcelain"))
+        }
+        ("daemon", "status") => {
+            if flag("--with-errors") {
+                options.push(format!("errors={}", WITH_ERRORS));
+            }
+            if let Some(n) = value("--with-errors") {
+                options.push(format!("errors={}", n));
+            }
+            if flag("--long") {
+                options.push(String::from("long"));
+            }
+            if flag("--json") {
+                options.push(String::from("json"));
+            }
+            if let Some(feature) = value("--features") {
+                options.push(format!("features={}", feature));
+            }
+        }
+        _ => (),
+    }
+    let payload = format!("{}?{}", normalized_args.1, options.join("&"));
+    let normalized_args = match options.is_empty() {
+        true => normalized_args,
+        false => (normalized_args.0, payload.as_str()),
+    };
+
     match normalized_args {
         ("daemon", "start") => daemon(),
-        _ => client(normalized_args),
+        ("daemon", "proxy") => std::process::exit(proxy::run(&paths().socket)),
+        ("gen-service", name) => gen_service(name, &flags, retry, timeout, via),
+        ("daemon", "config") => {
+            let response = request(normalized_args, retry, timeout, via);
+            print_config(&response, flag("--json"));
+        }
+        _ if options.iter().any(|option| option == "porcelain") => {
+            let snapshot = request(normalized_args, retry, timeout, via);
+            if let Some(path) = value("--save") {
+                let saved = std::fs::write(path, format!("{}\n", snapshot));
+                match stats::track_write(saved) {
+                    Ok(()) => (),
+                    Err(e) if e.kind() == ErrorKind::ReadOnlyFilesystem => {
+                        eprintln!("filesystem read-only: cannot save snapshot {}", path);
+                        std::process::exit(1);
+                    }
+                    Err(e) => {
+                        eprintln!("snapshot: bad write {}: {}", path, e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            if let Some(path) = value("--diff") {
+                let saved = std::fs::read_to_string(path).unwrap_or_else(|e| {
+                    eprintln!("snapshot: bad read {}: {}", path, e);
+                    std::process::exit(1);
+                });
+                let changes = snapshot::diff(&saved, &snapshot);
+                for change in &changes {
+                    println!("{}", change);
+                }
+                if !changes.is_empty() {
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => client(normalized_args, retry, timeout, via),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A service outside any config, its files in the test sandbox.
+    fn service(name: &str, exec: &str, args: &[&str], directives: &[(&str, &str)]) -> ArcService {
+        config::test_paths();
+        ArcService::new(ServiceDefinition {
+            name: format!("unit-{}", name),
+            exec: PathBuf::from(exec),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            directives: directives
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        })
+    }
+
+    /// Starts `service` and waits for it to fail; its failure.
+    fn failure(service: &ArcService) -> Failure {
+        service.start_as(&service.0.definition.name, Origin::Manual);
+        let deadline = Instant::now() + Duration::from_secs(20);
+        loop {
+            if let State::Failed(failure) = &service.0.status().state {
+                return failure.clone();
+            }
+            assert!(
+                Instant::now() < deadline,
+                "no failure: {}",
+                service.activity()
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn missing_executable_is_exec_not_found() {
+        let missing = service("missing", "/nonexistent/dctl-unit", &[], &[]);
+        assert_eq!(failure(&missing).code(), Code::ExecNotFound);
+    }
+
+    #[test]
+    fn unexecutable_file_is_permission_denied() {
+        let path = Path::new(&paths().runtime).join("unit-not-executable");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let denied = service("denied", path.to_str().unwrap(), &[], &[]);
+        assert_eq!(failure(&denied).code(), Code::PermissionDenied);
+    }
+
+    #[test]
+    fn oneshot_exit_code_is_exit_status() {
+        let job = service("exit", "/bin/sh", &["-c", "exit 3"], &[("Type", "oneshot")]);
+        let failure = failure(&job);
+        assert_eq!(failure.code(), Code::ExitStatus);
+        assert!(failure.reason().contains("3"), "{}", failure);
+    }
+
+    #[test]
+    fn oneshot_killed_is_signal() {
+        let job = service(
+            "signal",
+            "/bin/sh",
+            &["-c", "kill -9 $$"],
+            &[("Type", "oneshot")],
+        );
+        assert_eq!(failure(&job).code(), Code::Signal);
+    }
+
+    #[test]
+    fn crash_loop_is_start_limit() {
+        let crash = service(
+            "crash",
+            "/bin/sh",
+            &["-c", "exit 1"],
+            &[("RestartSec", "0")],
+        );
+        let failure = failure(&crash);
+        assert_eq!(failure.code(), Code::StartLimit);
+        assert!(failure.reason().ends_with("start limit hit"), "{}", failure);
+    }
+
+    #[test]
+    fn missing_namespace_is_netns_unavailable() {
+        let netns = service("netns", "/bin/true", &[], &[("NETNS", "dctl-unit-missing")]);
+        assert_eq!(failure(&netns).code(), Code::NetnsUnavailable);
+    }
+
+    #[test]
+    fn silent_supervisor_is_supervisor_error() {
+        let stuck = service("stuck", "/bin/sleep", &["30"], &[]);
+        stuck.start_as("unit-stuck", Origin::Manual);
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while stuck.0.pid.load(Ordering::Acquire) == 0 {
+            assert!(Instant::now() < deadline, "never ran");
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        // the supervisor beats every tick; age the heartbeat right before
+        // each sweep until one sees it silent
+        while !matches!(stuck.0.status().state, State::Failed(_)) {
+            assert!(Instant::now() < deadline, "never swept");
+            stuck.0.status().heartbeat = Instant::now() - Duration::from_secs(HEARTBEAT_SEC + 1);
+            stuck.sweep("unit-stuck");
+        }
+        match &stuck.0.status().state {
+            State::Failed(failure) => assert_eq!(failure.code(), Code::SupervisorError),
+            _ => unreachable!(),
+        }
+        stuck.stop();
+    }
+
+    #[test]
+    fn status_full_golden() {
+        let web = service("full", "/bin/true", &[], &[]);
+        let stack = ServiceStack::new(HashMap::from([(String::from("unit-full"), web)]));
+        assert_eq!(
+            stack.status_full("unit-full"),
+            "[false] 0 autostart\nargv: never spawned"
+        );
+
+        let job = service(
+            "full-failed",
+            "/bin/sh",
+            &["-c", "exit 2"],
+            &[("Type", "oneshot")],
+        );
+        failure(&job);
+        let stack = ServiceStack::new(HashMap::from([(String::from("unit-full-failed"), job)]));
+        let full = stack.status_full("unit-full-failed");
+        let lines: Vec<&str> = full.lines().collect();
+        assert_eq!(
+            lines[0],
+            "[false] 0 manual [log,oneshot] (exit status: 2 [exit-status])"
+        );
+        assert_eq!(lines[1], "failure: exit-status");
+        // the executable as resolved when it was spawned
+        assert!(lines[2].starts_with("argv: /"), "{}", full);
+        assert!(lines[2].ends_with("sh -c exit 2"), "{}", full);
+        assert!(lines[3].starts_with("env: "), "{}", full);
+        assert_eq!(lines.len(), 4, "{}", full);
+    }
+
+    #[test]
+    fn abandoned_wait_unregisters_its_waiter() {
+        let waiting = service("waiting", "/bin/true", &[], &[]);
+        waiting
+            .0
+            .set_state(State::Waiting(String::from("backing mount /x is gone")));
+        let stack = ServiceStack::new(HashMap::from([(String::from("unit-waiting"), waiting)]));
+        let gone = AtomicBool::new(false);
+        let before = stats::WAITERS.load(Ordering::Relaxed);
+
+        thread::scope(|scope| {
+            let wait = scope.spawn(|| stack.wait("unit-waiting", || gone.load(Ordering::Relaxed)));
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while stats::WAITERS.load(Ordering::Relaxed) == before {
+                assert!(Instant::now() < deadline, "wait never registered");
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(stats::WAITERS.load(Ordering::Relaxed), before + 1);
+
+            gone.store(true, Ordering::Relaxed);
+            assert_eq!(wait.join().unwrap(), None);
+        });
+        assert_eq!(stats::WAITERS.load(Ordering::Relaxed), before);
+    }
+
+    #[test]
+    fn why_explains_a_wait_for_a_mount() {
+        let waiting = service("why-waiting", "/bin/true", &[], &[]);
+        waiting
+            .0
+            .set_state(State::Waiting(String::from("backing mount /x is gone")));
+        let stack = ServiceStack::new(HashMap::from([(String::from("unit-why-waiting"), waiting)]));
+
+        let why = stack.why("unit-why-waiting");
+        assert!(
+            why.starts_with("unit-why-waiting is activating for "),
+            "{}",
+            why
+        );
+        assert!(
+            why.contains("\nwaiting: backing mount /x is gone, spawned once that clears"),
+            "{}",
+            why
+        );
+    }
+
+    #[test]
+    fn dependents_stop_before_what_they_require() {
+        let stack: HashMap<String, ArcService> = [
+            ("web", "unit-api,unit-cache"),
+            ("api", "unit-db"),
+            ("worker", "unit-db"),
+            ("cache", ""),
+            ("db", ""),
+            ("lone", ""),
+            // a cycle is cut, not followed forever
+            ("ping", "unit-pong"),
+            ("pong", "unit-ping"),
+        ]
+        .into_iter()
+        .map(|(name, requires)| {
+            let service = service(name, "/bin/true", &[], &[("Requires", requires)]);
+            (format!("unit-{}", name), service)
+        })
+        .collect();
+
+        let groups = ServiceStack::stop_groups(&stack);
+        let position = |name: &str| groups.iter().position(|g| g.iter().any(|n| n == name));
+        assert_eq!(position("unit-web"), Some(0));
+        assert_eq!(position("unit-lone"), Some(0));
+        assert_eq!(position("unit-worker"), Some(0));
+        assert_eq!(position("unit-api"), Some(1));
+        assert_eq!(position("unit-cache"), Some(1));
+        assert_eq!(position("unit-db"), Some(2));
+        assert!(position("unit-ping").is_some() && position("unit-pong").is_some());
+        assert_eq!(groups.iter().map(Vec::len).sum::<usize>(), stack.len());
+    }
+
+    #[test]
+    fn read_only_verbs_are_not_mutating() {
+        for args in [
+            ("status", "web"),
+            ("why", "web"),
+            ("log", "web?lines=5"),
+            ("daemon", "status"),
+            ("daemon", "config"),
+            ("daemon", "doctor"),
+        ] {
+            assert!(!mutating(args), "{:?}", args);
+        }
+        for args in [
+            ("start", "web"),
+            ("enable", "web"),
+            ("daemon", "reload"),
+            ("daemon", "stop"),
+        ] {
+            assert!(mutating(args), "{:?}", args);
+        }
     }
 }
@@ -0,0 +1,28 @@
+//! Bounds on strings the daemon keeps for its whole life, and the footprint
+//! of those structures as reported by `daemon#info`.
+//!
+//! Sizes are computed from what the structures hold, not measured by the
+//! allocator, so they are approximations.
+
+/// `line` cut at a char boundary to at most `max` bytes plus a `…` marker.
+pub fn cap(mut line: String, max: usize) -> String {
+    if line.len() > max {
+        let mut end = max;
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        line.truncate(end);
+        line.push('…');
+    }
+    line
+}
+
+/// `memory: a Nb, b Nb` for `daemon#info`.
+pub fn render(parts: &[(&str, usize)]) -> String {
+    let total: usize = parts.iter().map(|(_, bytes)| bytes).sum();
+    let parts: Vec<String> = parts
+        .iter()
+        .map(|(label, bytes)| format!("{} {}b", label, bytes))
+        .collect();
+    format!("memory: {}b ({})", total, parts.join(", "))
+}
@@ -0,0 +1,65 @@
+//! Which mount an executable lives on, read from /proc/self/mountinfo.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Absolute path of `command`, searching PATH like exec does for bare names.
+pub fn resolve(command: &Path) -> Option<PathBuf> {
+    if command.components().count() > 1 {
+        return std::fs::canonicalize(command).ok();
+    }
+
+    std::env::var_os("PATH")?
+        .to_str()?
+        .split(':')
+        .map(|dir| Path::new(dir).join(command))
+        .find(|path| path.is_file())
+        .and_then(|path| std::fs::canonicalize(path).ok())
+}
+
+/// Mount points with their `major:minor` device, undoing mountinfo's octal escapes.
+fn mounts() -> Vec<(String, String)> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            Some((fields.get(2)?.to_string(), unescape(fields.get(4)?)))
+        })
+        .collect()
+}
+
+fn unescape(field: &str) -> String {
+    let mut out = String::new();
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        let octal: String = chars.by_ref().take(3).collect();
+        match u8::from_str_radix(&octal, 8) {
+            Ok(byte) => out.push(byte as char),
+            Err(_) => out.push_str(&octal),
+        }
+    }
+    out
+}
+
+/// Mount point of the filesystem (st_dev) holding `path`.
+pub fn backing_mount(path: &Path) -> Option<String> {
+    let dev = std::fs::metadata(path).ok()?.dev();
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let device = format!("{}:{}", major, minor);
+
+    mounts()
+        .into_iter()
+        .filter(|(dev, point)| *dev == device && path.starts_with(point))
+        .map(|(_, point)| point)
+        .max_by_key(|point| point.len())
+}
+
+pub fn is_mounted(point: &str) -> bool {
+    mounts().iter().any(|(_, mounted)| mounted == point)
+}
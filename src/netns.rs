@@ -0,0 +1,99 @@
+//! `NETNS=name`: services started inside an existing network namespace,
+//! NETNS_PATH/<name> as made by `ip netns add`.
+//!
+//! The supervisor opens the namespace and the child enters it between fork
+//! and exec. A failing pre_exec only reports an errno, so entering is tried
+//! on a throwaway thread first to fail the spawn with a precise reason; a
+//! namespace switch applies to the calling thread only.
+
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::config::NETNS_PATH;
+use crate::libc::setns_net_;
+
+pub fn path(name: &str) -> PathBuf {
+    Path::new(NETNS_PATH).join(name)
+}
+
+/// Whether the kernel has network namespaces at all.
+pub fn supported() -> bool {
+    Path::new("/proc/self/ns/net").exists()
+}
+
+/// The namespace `name`, checked to be enterable.
+pub fn open(name: &str) -> Result<File, String> {
+    let path = path(name);
+    let file =
+        File::open(&path).map_err(|e| format!("netns: bad open {}: {}", path.display(), e))?;
+
+    let fd = file.as_raw_fd();
+    let entered = thread::scope(|scope| scope.spawn(|| enter(fd)).join());
+    match entered {
+        Ok(Ok(())) => Ok(file),
+        Ok(Err(e)) => Err(format!("netns: bad setns {}: {}", path.display(), e)),
+        Err(_) => Err(format!(
+            "netns: bad setns {}: thread panicked",
+            path.display()
+        )),
+    }
+}
+
+/// Enters the namespace open at `fd`; runs in the forked child.
+pub fn enter(fd: i32) -> std::io::Result<()> {
+    match setns_net_(fd) {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// `net:[inode]` of the namespace at `path`, as /proc shows it.
+pub fn id(path: &Path) -> Option<String> {
+    Some(format!("net:[{}]", std::fs::metadata(path).ok()?.ino()))
+}
+
+/// The network namespace process `pid` is in.
+pub fn of(pid: u32) -> Option<String> {
+    let link = std::fs::read_link(format!("/proc/{}/ns/net", pid)).ok()?;
+    Some(link.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespaces_live_under_netns_path() {
+        assert_eq!(path("vpn"), Path::new(NETNS_PATH).join("vpn"));
+    }
+
+    #[test]
+    fn missing_namespace_names_its_path() {
+        let e = open("dctl-unit-missing").unwrap_err();
+        assert_eq!(
+            e,
+            format!(
+                "netns: bad open {}/dctl-unit-missing: No such file or directory (os error 2)",
+                NETNS_PATH
+            )
+        );
+    }
+
+    #[test]
+    fn entering_what_is_no_namespace_fails() {
+        let file = File::open("/proc/self/status").unwrap();
+        let e = enter(file.as_raw_fd()).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(22));
+    }
+
+    #[test]
+    fn id_of_a_namespace_file_matches_the_process_in_it() {
+        assert!(supported());
+        let ours = of(std::process::id()).unwrap();
+        assert!(ours.starts_with("net:["), "{}", ours);
+        assert_eq!(id(Path::new("/proc/self/ns/net")), Some(ours));
+    }
+}
@@ -0,0 +1,87 @@
+//! Per-service output files: what a service writes to stdout and stderr is
+//! appended to `SERVICE_LOG_PATH/<name>/service.log`, kept across restarts
+//! and stops, and served back by `log#name`.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::paths;
+use crate::definition;
+
+/// Where the log of `name` lives, for a name that stays in its directory.
+pub fn path(name: &str) -> Result<PathBuf, String> {
+    definition::check_name(name).map_err(|e| format!("log: {}", e))?;
+    Ok(PathBuf::from(&paths().service_log)
+        .join(name)
+        .join("service.log"))
+}
+
+/// The log of `name` opened for appending, with a separator line marking
+/// spawn number `spawn`.
+pub fn open(name: &str, spawn: u64) -> std::io::Result<File> {
+    let path = path(name).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    file.write_all(format!("--- dctl: spawn {} at {} ---\n", spawn, now).as_bytes())?;
+    Ok(file)
+}
+
+/// The last `lines` lines of the log of `name`, read backwards from the end
+/// so a big log costs no more than the lines asked for.
+pub fn tail(name: &str, lines: usize) -> String {
+    let path = match path(name) {
+        Ok(path) => path,
+        Err(e) => return e,
+    };
+    let mut file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) => return format!("log: bad open {}: {}", path.display(), e),
+    };
+
+    let mut end = file.seek(SeekFrom::End(0)).unwrap_or(0);
+    let mut tail: Vec<u8> = Vec::new();
+    while end > 0 && tail.iter().filter(|byte| **byte == b'\n').count() <= lines {
+        let step = end.min(8192);
+        end -= step;
+        let mut chunk = vec![0; step as usize];
+        if file.seek(SeekFrom::Start(end)).is_err() || file.read_exact(&mut chunk).is_err() {
+            break;
+        }
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+    }
+
+    let text = String::from_utf8_lossy(&tail);
+    let all: Vec<&str> = text.lines().collect();
+    all[all.len().saturating_sub(lines)..].join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_outside_the_log_dir_are_refused() {
+        crate::config::test_paths();
+        for name in ["..", ".", "../../etc/passwd", "-n", ""] {
+            assert!(path(name).is_err(), "{:?}", name);
+            assert!(
+                tail(name, 10).starts_with("log: bad service name"),
+                "{:?}",
+                name
+            );
+        }
+        assert!(path("web").unwrap().ends_with("web/service.log"));
+    }
+}
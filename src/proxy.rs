@@ -0,0 +1,122 @@
+//! `dctl proxy` and `--via=COMMAND`: the control protocol over a pipe, for a
+//! daemon that is only reachable through something like
+//! `adb exec-out dctl proxy`.
+//!
+//! A request is everything up to EOF and the answer everything up to the
+//! daemon closing, so a pipe carries it as well as the socket does as long
+//! as each end passes EOF on: the proxy half-closes the socket once its
+//! stdin ends, and exits once the socket ends.
+
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::END_MARKER;
+
+/// Shuttles stdin to the daemon at `path` and its answer to stdout. The
+/// exit code is 0 if both directions ended cleanly, 1 otherwise.
+pub fn run(path: &str) -> i32 {
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("socket: bad connect({}): {}", path, e);
+            return 1;
+        }
+    };
+    let mut upstream = match stream.try_clone() {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            eprintln!("proxy: bad clone: {}", e);
+            return 1;
+        }
+    };
+
+    let sender = thread::spawn(move || {
+        let copied = std::io::copy(&mut std::io::stdin().lock(), &mut upstream);
+        let _ = upstream.shutdown(Shutdown::Write);
+        copied
+    });
+
+    let mut downstream = stream;
+    let mut stdout = std::io::stdout().lock();
+    let received = std::io::copy(&mut downstream, &mut stdout).and_then(|_| stdout.flush());
+
+    // the daemon may answer and close before stdin ends; that is no error
+    let sent = match sender.is_finished() {
+        true => sender.join().unwrap_or(Ok(0)),
+        false => Ok(0),
+    };
+    match (sent, received) {
+        (Ok(_), Ok(())) => 0,
+        (Err(e), _) => {
+            eprintln!("proxy: bad send: {}", e);
+            1
+        }
+        (_, Err(e)) => {
+            eprintln!("proxy: bad receive: {}", e);
+            1
+        }
+    }
+}
+
+/// One request through `sh -c command`, whose stdin and stdout must reach a
+/// `dctl proxy`. Answers like a socket exchange: None if the response came
+/// back truncated, Err only when `timeout` ran out.
+pub fn exchange(
+    command: &str,
+    message: &str,
+    timeout: Option<Duration>,
+) -> std::io::Result<Option<String>> {
+    let mut child = match Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("proxy: bad spawn {:?}: {}", command, e);
+            std::process::exit(1);
+        }
+    };
+
+    // dropping stdin is the EOF that ends the request
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(message.as_bytes());
+    }
+    let Some(mut stdout) = child.stdout.take() else {
+        return Ok(None);
+    };
+    let (done, response) = mpsc::channel();
+    thread::spawn(move || {
+        let mut response = String::new();
+        let _ = stdout.read_to_string(&mut response);
+        let _ = done.send(response);
+    });
+
+    let response = match timeout {
+        Some(timeout) => response.recv_timeout(timeout).ok(),
+        None => response.recv().ok(),
+    };
+    let Some(response) = response else {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(std::io::ErrorKind::TimedOut.into());
+    };
+
+    let status = child.wait();
+    match (response.strip_suffix(END_MARKER), status) {
+        (Some(response), _) => Ok(Some(response.to_string())),
+        // nothing reached a proxy: adb missing, device gone
+        (None, Ok(status)) if !status.success() => {
+            eprintln!("proxy: {:?} failed: {}", command, status);
+            std::process::exit(1);
+        }
+        (None, _) => Ok(None),
+    }
+}
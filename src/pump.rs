@@ -0,0 +1,100 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::config::{LOG_BURST, LOG_RATE, LOG_SUMMARY_SEC};
+
+/// Token bucket shared by the stdout and stderr pumps of one spawn.
+pub struct Limiter {
+    tokens: f64,
+    refilled: Instant,
+    dropped: u64,
+    interval: Instant,
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Self {
+            tokens: LOG_BURST as f64,
+            refilled: Instant::now(),
+            dropped: 0,
+            interval: Instant::now(),
+        }
+    }
+
+    fn admit(&mut self) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * LOG_RATE as f64;
+        self.tokens = (self.tokens + refill).min(LOG_BURST as f64);
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            self.dropped += 1;
+            false
+        }
+    }
+
+    /// One line per interval summing up what was dropped in it.
+    fn summary(&mut self, force: bool) -> Option<String> {
+        let elapsed = self.interval.elapsed();
+        if self.dropped == 0 || (!force && elapsed < Duration::from_secs(LOG_SUMMARY_SEC)) {
+            return None;
+        }
+
+        let line = format!(
+            "dctl: dropped {} lines in last {}s\n",
+            self.dropped,
+            elapsed.as_secs().max(1)
+        );
+        self.dropped = 0;
+        self.interval = Instant::now();
+        Some(line)
+    }
+}
+
+/// Copies `source` to `sink` line by line until EOF, dropping lines over the
+/// rate limit. Decisions never block the read side.
+pub fn pump(
+    source: impl Read,
+    mut sink: impl Write,
+    limiter: &Mutex<Limiter>,
+    dropped: &AtomicU64,
+) {
+    let mut source = BufReader::new(source);
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        match source.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+
+        let mut limiter = limiter.lock().unwrap_or_else(PoisonError::into_inner);
+        let admit = limiter.admit();
+        let summary = limiter.summary(false);
+        drop(limiter);
+
+        if let Some(summary) = summary {
+            let _ = sink.write_all(summary.as_bytes());
+        }
+        if admit {
+            let _ = sink.write_all(&line);
+        } else {
+            dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let summary = limiter
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .summary(true);
+    if let Some(summary) = summary {
+        let _ = sink.write_all(summary.as_bytes());
+    }
+    let _ = sink.flush();
+}
@@ -0,0 +1,83 @@
+//! Arrival order for mutating requests on one service: each takes a ticket
+//! when it comes in and runs once every earlier ticket for that name is
+//! done, while other services go on in parallel. Read-only verbs never
+//! queue.
+
+use std::collections::HashMap;
+use std::sync::{Condvar, LazyLock, Mutex, PoisonError};
+
+use crate::config::SERVICE_QUEUE_MAX;
+
+#[derive(Default)]
+struct Line {
+    /// Ticket handed to the next arrival.
+    next: u64,
+    /// Ticket allowed to run.
+    serving: u64,
+}
+
+static LINES: LazyLock<Mutex<HashMap<String, Line>>> = LazyLock::new(Mutex::default);
+static TURN: Condvar = Condvar::new();
+
+/// Held while a request runs; the next ticket for the name goes when dropped.
+pub struct Turn(String);
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        let mut lines = LINES.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(line) = lines.get_mut(&self.0) {
+            line.serving += 1;
+            if line.serving == line.next {
+                lines.remove(&self.0);
+            }
+        }
+        TURN.notify_all();
+    }
+}
+
+/// Waits for the turn of a new request on `name`, or refuses it when
+/// SERVICE_QUEUE_MAX requests are already running or waiting there.
+pub fn enter(name: &str) -> Result<Turn, String> {
+    let mut lines = LINES.lock().unwrap_or_else(PoisonError::into_inner);
+    let line = lines.entry(name.to_string()).or_default();
+    let depth = line.next - line.serving;
+    if depth >= SERVICE_QUEUE_MAX as u64 {
+        return Err(format!("queue: {}: busy, {} requests queued", name, depth));
+    }
+    let ticket = line.next;
+    line.next += 1;
+
+    while lines.get(name).is_some_and(|line| line.serving != ticket) {
+        lines = TURN.wait(lines).unwrap_or_else(PoisonError::into_inner);
+    }
+    Ok(Turn(name.to_string()))
+}
+
+/// Requests on `name` running or waiting, this one excluded if it queued.
+pub fn depth(name: &str) -> u64 {
+    LINES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(name)
+        .map_or(0, |line| line.next - line.serving)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_panic_holding_the_lines_poisons_nothing() {
+        let _ = std::thread::spawn(|| {
+            let _lines = LINES.lock().unwrap();
+            panic!("poisoning the lines");
+        })
+        .join();
+        assert!(LINES.is_poisoned());
+
+        let turn = enter("queue-test-poison").unwrap();
+        assert_eq!(depth("queue-test-poison"), 1);
+        drop(turn);
+        assert_eq!(depth("queue-test-poison"), 0);
+    }
+}
@@ -0,0 +1,126 @@
+//! Startup reconciliation of the files the daemon leaves behind.
+//!
+//! Runs once the socket is claimed, so no other daemon can be using them.
+//! What can be fixed safely is fixed and logged: missing read bits are
+//! added and world write is dropped on files the daemon owns, and the
+//! temporary file of an interrupted autostart write is removed. Anything
+//! else is left alone and reported by `daemon#doctor`.
+
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::{info, warn};
+
+use crate::config::{paths, FILE_MODE, RUNTIME_DIR_MODE};
+use crate::libc::geteuid_;
+
+/// What the last pass chose not to touch.
+static UNTOUCHED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn untouched() -> Vec<String> {
+    UNTOUCHED.lock().unwrap().clone()
+}
+
+/// Checks the socket directory, log, autostart file and the runtime dirs
+/// against the expected modes; `services` are the configured names.
+pub fn reconcile<'a>(services: impl Iterator<Item = &'a String>) {
+    let mut untouched = Vec::new();
+    let uid = geteuid_();
+
+    let socket_dir = Path::new(&paths().socket)
+        .parent()
+        .unwrap_or(Path::new("/"));
+    if let Ok(metadata) = std::fs::metadata(socket_dir) {
+        // world writable without the sticky bit lets anyone replace the socket
+        if metadata.mode() & 0o1002 == 0o002 {
+            untouched.push(format!(
+                "socket: {} is world writable",
+                socket_dir.display()
+            ));
+        }
+    }
+
+    let tmp = format!("{}.tmp", &paths().autostart);
+    if Path::new(&tmp).exists() {
+        match std::fs::remove_file(&tmp) {
+            Ok(()) => info!("repair: removed interrupted write {}", tmp),
+            Err(e) => untouched.push(format!("autostart: stale {}: {}", tmp, e)),
+        }
+    }
+
+    mode(
+        "log",
+        Path::new(&paths().log),
+        FILE_MODE,
+        uid,
+        &mut untouched,
+    );
+    mode(
+        "autostart",
+        Path::new(&paths().autostart),
+        FILE_MODE,
+        uid,
+        &mut untouched,
+    );
+
+    let services: Vec<&String> = services.collect();
+    if let Ok(entries) = std::fs::read_dir(&paths().runtime) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let known = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| services.iter().any(|service| *service == name));
+            match known {
+                true => mode("runtime", &path, RUNTIME_DIR_MODE, uid, &mut untouched),
+                // its processes may outlive the previous daemon, so keep it
+                false => untouched.push(format!(
+                    "runtime: {} belongs to no configured service",
+                    path.display()
+                )),
+            }
+        }
+    }
+
+    for message in &untouched {
+        warn!("repair: left alone: {}", message);
+    }
+    *UNTOUCHED.lock().unwrap() = untouched;
+}
+
+/// Adds the missing bits of `want` and drops world write on `path`, if it
+/// exists and belongs to `uid`.
+fn mode(label: &str, path: &Path, want: u32, uid: u32, untouched: &mut Vec<String>) {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return;
+    };
+    let have = metadata.mode() & 0o7777;
+    let fixed = (have | want) & !0o002;
+    if have == fixed {
+        return;
+    }
+
+    if metadata.file_type().is_symlink() || metadata.uid() != uid {
+        untouched.push(format!(
+            "{}: {} is mode {:o} owned by uid {}, want {:o}",
+            label,
+            path.display(),
+            have,
+            metadata.uid(),
+            fixed
+        ));
+        return;
+    }
+
+    match std::fs::set_permissions(path, std::fs::Permissions::from_mode(fixed)) {
+        Ok(()) => info!(
+            "repair: {} {} mode {:o} -> {:o}",
+            label,
+            path.display(),
+            have,
+            fixed
+        ),
+        Err(e) => untouched.push(format!("{}: bad chmod {}: {}", label, path.display(), e)),
+    }
+}
@@ -0,0 +1,91 @@
+//! Restarts of all services together: a fault below the services, like a
+//! bad kernel module, can keep each of them under START_LIMIT while dozens
+//! of restarts an hour pile up across the whole stack.
+//!
+//! Counts are kept in one bucket per minute for the last hour, so recording
+//! a restart touches one bucket and nothing grows with the rate.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
+
+use log::warn;
+
+use crate::config::{UNSTABLE_HOOK, UNSTABLE_RESTARTS};
+use crate::{events, hooks};
+
+const MINUTES: u64 = 60;
+
+static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+static TOTAL: AtomicU64 = AtomicU64::new(0);
+/// (minute since START, restarts in it); a stale minute is reused.
+static BUCKETS: Mutex<[(u64, u64); MINUTES as usize]> = Mutex::new([(0, 0); MINUTES as usize]);
+/// Set once the last hour went over UNSTABLE_RESTARTS, until it is under.
+static UNSTABLE: AtomicBool = AtomicBool::new(false);
+
+fn minute() -> u64 {
+    START.elapsed().as_secs() / 60
+}
+
+/// Restarts in the last `window` minutes, the current one included.
+fn within(buckets: &[(u64, u64)], now: u64, window: u64) -> u64 {
+    buckets
+        .iter()
+        .filter(|(minute, _)| now - minute < window)
+        .map(|(_, count)| count)
+        .sum()
+}
+
+/// Counts an exit of `name` that Restart= respawns, flagging the system
+/// unstable when the last hour reaches UNSTABLE_RESTARTS.
+pub fn record(name: &str) {
+    TOTAL.fetch_add(1, Ordering::Relaxed);
+    let now = minute();
+    let hour = {
+        let mut buckets = BUCKETS.lock().unwrap();
+        let bucket = &mut buckets[(now % MINUTES) as usize];
+        if bucket.0 != now {
+            *bucket = (now, 0);
+        }
+        bucket.1 += 1;
+        within(&*buckets, now, MINUTES)
+    };
+
+    if UNSTABLE_RESTARTS == 0 || hour < UNSTABLE_RESTARTS {
+        UNSTABLE.store(false, Ordering::Relaxed);
+        return;
+    }
+    if UNSTABLE.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    warn!(
+        "system: UNSTABLE: {} restarts across all services in the last hour, {} the latest",
+        hour, name
+    );
+    events::record(format!(
+        "system-unstable: {} restarts in the last hour, last {}",
+        hour, name
+    ));
+    if !UNSTABLE_HOOK.is_empty() {
+        let env = vec![(String::from("DCTL_RESTARTS"), hour.to_string())];
+        hooks::fire("daemon", "UNSTABLE", UNSTABLE_HOOK, UNSTABLE_HOOK, env);
+    }
+}
+
+/// The `daemon#info` line.
+pub fn render() -> String {
+    let now = minute();
+    let buckets = BUCKETS.lock().unwrap();
+    let unstable = match UNSTABLE.load(Ordering::Relaxed) {
+        true => ", unstable",
+        false => "",
+    };
+    format!(
+        "restarts: {} total, {}/{}/{} in the last 5/15/60 min{}",
+        TOTAL.load(Ordering::Relaxed),
+        within(&*buckets, now, 5),
+        within(&*buckets, now, 15),
+        within(&*buckets, now, 60),
+        unstable
+    )
+}
@@ -0,0 +1,124 @@
+//! Client side of `dctl gen-service NAME --exec=PATH ...`: one config line
+//! built from flags, checked with the daemon's own parser and written into
+//! the config file in place of, or after, the existing lines.
+
+use std::io::{ErrorKind, Write};
+
+use crate::{definition, stats};
+
+/// `Key=value`, quoted when the value has spaces.
+fn directive(key: &str, value: &str) -> String {
+    match value.contains(char::is_whitespace) {
+        true => format!("{}=\"{}\"", key, value),
+        false => format!("{}={}", key, value),
+    }
+}
+
+/// The config line for `name`; `directives` come before the command as the
+/// parser wants them.
+pub fn line(
+    name: &str,
+    exec: &str,
+    args: &[&str],
+    directives: &[(&str, &str)],
+) -> Result<String, String> {
+    definition::check_name(name).map_err(|e| format!("gen-service: {}", e))?;
+    if let Some(arg) = args.iter().find(|arg| arg.contains(char::is_whitespace)) {
+        return Err(format!(
+            "gen-service: argument {:?} has spaces, which a config line can't carry; use a wrapper script",
+            arg
+        ));
+    }
+
+    let mut words = vec![name.to_string()];
+    words.extend(directives.iter().map(|(key, value)| directive(key, value)));
+    // a plain first word would be taken for a directive if it looked like one
+    match exec.contains(char::is_whitespace) || exec.contains('=') {
+        true => words.push(directive("EXEC", exec)),
+        false => words.push(exec.to_string()),
+    }
+    words.extend(args.iter().map(|arg| arg.to_string()));
+    let line = words.join(" ");
+
+    definition::parse(&line).map_err(|e| format!("gen-service: {}", e))?;
+    Ok(line)
+}
+
+/// Puts `line` into the config at `path`, replacing the line of the same
+/// service only with `force`; true if it replaced one. The file is swapped
+/// in one rename like the autostart file.
+pub fn write(path: &str, name: &str, line: &str, force: bool) -> Result<bool, String> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("config: bad read {}: {}", path, e)),
+    };
+
+    let ours = |existing: &str| existing.split_whitespace().next() == Some(name);
+    let replaced = contents.lines().any(ours);
+    if replaced && !force {
+        return Err(format!(
+            "gen-service: {} already exists in {}, pass --force to replace it",
+            name, path
+        ));
+    }
+    let mut lines: Vec<&str> = contents
+        .lines()
+        .map(|existing| if ours(existing) { line } else { existing })
+        .collect();
+    if !replaced {
+        lines.push(line);
+    }
+
+    let tmp = format!("{}.tmp", path);
+    let written = std::fs::File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(format!("{}\n", lines.join("\n")).as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&tmp, path));
+    stats::track_write(written).map_err(|e| {
+        let _ = std::fs::remove_file(&tmp);
+        failed(path, &e)
+    })?;
+    Ok(replaced)
+}
+
+fn failed(path: &str, e: &std::io::Error) -> String {
+    match e.kind() {
+        ErrorKind::ReadOnlyFilesystem => {
+            format!("filesystem read-only: cannot update config {}", path)
+        }
+        _ => format!("config: bad write {}: {}", path, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_only_config_is_named() {
+        let e = std::io::Error::from_raw_os_error(stats::EROFS);
+        assert_eq!(
+            failed("/data/config", &e),
+            "filesystem read-only: cannot update config /data/config"
+        );
+        let e = std::io::Error::from_raw_os_error(28);
+        assert_eq!(
+            failed("/data/config", &e),
+            "config: bad write /data/config: No space left on device (os error 28)"
+        );
+    }
+
+    #[test]
+    fn bad_names_make_no_line() {
+        for name in ["", "..", ".", "a/b", "-x", "a b"] {
+            assert!(line(name, "/bin/true", &[], &[]).is_err(), "{:?}", name);
+        }
+        assert_eq!(
+            line("web", "/bin/true", &["-v"], &[]),
+            Ok(String::from("web /bin/true -v"))
+        );
+    }
+}
@@ -0,0 +1,56 @@
+//! Secret environment values: passed to the child intact, shown as `***`
+//! wherever the daemon prints them (`show`, `status --full`, the log, events
+//! and traces).
+//!
+//! A variable is secret if `SECRET_ENV=A,B` lists it or, with
+//! SECRET_HEURISTIC, its name contains TOKEN, KEY or PASSWORD. Masking goes
+//! by value, so a secret is caught wherever it was pasted: in an argument,
+//! a hook command or an error message.
+
+use std::cmp::Reverse;
+
+use crate::config::SECRET_HEURISTIC;
+
+pub const MASK: &str = "***";
+const HINTS: &[&str] = &["TOKEN", "KEY", "PASSWORD"];
+
+/// Whether variable `key` is secret, given the names SECRET_ENV lists.
+pub fn secret(key: &str, listed: &[&str]) -> bool {
+    listed.contains(&key)
+        || (SECRET_HEURISTIC && {
+            let key = key.to_ascii_uppercase();
+            HINTS.iter().any(|hint| key.contains(hint))
+        })
+}
+
+/// `text` with every occurrence of `values` replaced by MASK.
+pub fn mask(text: &str, values: &[String]) -> String {
+    let mut values: Vec<&String> = values.iter().filter(|value| !value.is_empty()).collect();
+    // longest first, so a secret containing another is masked whole
+    values.sort_by_key(|value| Reverse(value.len()));
+    values.into_iter().fold(text.to_string(), |text, value| {
+        text.replace(value.as_str(), MASK)
+    })
+}
+
+/// `key=value` options of a request with secret values masked, for traces;
+/// `listed` are the SECRET_ENV names of every service.
+pub fn mask_options(message: &str, listed: &[&str], values: &[String]) -> String {
+    let masked = match message.split_once('?') {
+        Some((head, options)) => {
+            let options: Vec<String> = options
+                .split('&')
+                .map(|option| match option.strip_prefix("env=") {
+                    Some(pair) => match pair.split_once('=') {
+                        Some((key, _)) if secret(key, listed) => format!("env={}={}", key, MASK),
+                        _ => option.to_string(),
+                    },
+                    None => option.to_string(),
+                })
+                .collect();
+            format!("{}?{}", head, options.join("&"))
+        }
+        None => message.to_string(),
+    };
+    mask(&masked, values)
+}
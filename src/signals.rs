@@ -0,0 +1,36 @@
+//! Signal names as written in the config file, mapped to their numbers on
+//! Linux and Android. `SIGUSR1`, `USR1` and `10` all name the same signal.
+
+const SIGNALS: &[(&str, u32)] = &[
+    ("HUP", 1),
+    ("INT", 2),
+    ("QUIT", 3),
+    ("KILL", 9),
+    ("USR1", 10),
+    ("USR2", 12),
+    ("ALRM", 14),
+    ("TERM", 15),
+    ("CONT", 18),
+    ("STOP", 19),
+    ("WINCH", 28),
+    ("PWR", 30),
+];
+
+pub fn parse(name: &str) -> Option<u32> {
+    if let Ok(number) = name.parse::<u32>() {
+        return (1..65).contains(&number).then_some(number);
+    }
+    let name = name.strip_prefix("SIG").unwrap_or(name);
+    SIGNALS
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, number)| *number)
+}
+
+/// `SIGUSR1`, or the number for signals without a name here.
+pub fn name(number: u32) -> String {
+    match SIGNALS.iter().find(|(_, known)| *known == number) {
+        Some((name, _)) => format!("SIG{}", name),
+        None => number.to_string(),
+    }
+}
@@ -0,0 +1,73 @@
+//! Client side of `status --save=FILE` and `status --diff=FILE`.
+//!
+//! A snapshot is the `daemon#status?porcelain` answer as is: one
+//! `name activity pid spawns` line per service, sorted by name. The daemon
+//! only has to keep that format stable; comparing happens here.
+
+use std::collections::BTreeMap;
+
+struct Entry<'a> {
+    activity: &'a str,
+    spawns: u64,
+}
+
+fn parse(snapshot: &str) -> BTreeMap<&str, Entry<'_>> {
+    snapshot
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields.as_slice() {
+                [name, activity, _pid, spawns] => Some((
+                    *name,
+                    Entry {
+                        activity,
+                        spawns: spawns.parse().unwrap_or(0),
+                    },
+                )),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// What changed from `old` to `new`, grouped into started, stopped, state
+/// changed and restarted lines; empty if nothing did.
+pub fn diff(old: &str, new: &str) -> Vec<String> {
+    let (old, new) = (parse(old), parse(new));
+    let mut started = Vec::new();
+    let mut stopped = Vec::new();
+    let mut changed = Vec::new();
+    let mut restarted = Vec::new();
+
+    for (name, entry) in &new {
+        let before = old.get(name);
+        let activity = before.map_or("inactive", |before| before.activity);
+        match (activity, entry.activity) {
+            (before, now) if before == now => (),
+            (_, "active") => {
+                started.push(name.to_string());
+                continue;
+            }
+            ("active", "inactive" | "completed") => stopped.push(name.to_string()),
+            (before, now) => changed.push(format!("{} {} -> {}", name, before, now)),
+        }
+        // the spawn of a start is not a restart
+        if let Some(before) = before.filter(|before| entry.spawns > before.spawns) {
+            restarted.push(format!("{} (+{})", name, entry.spawns - before.spawns));
+        }
+    }
+    for name in old.keys().filter(|name| !new.contains_key(*name)) {
+        stopped.push(format!("{} (gone)", name));
+    }
+
+    [
+        ("started", started),
+        ("stopped", stopped),
+        ("state changed", changed),
+        ("restarted", restarted),
+    ]
+    .into_iter()
+    .filter(|(_, names)| !names.is_empty())
+    .map(|(group, names)| format!("{}: {}", group, names.join(", ")))
+    .collect()
+}
@@ -0,0 +1,39 @@
+//! Daemon-wide counters and flags reported by `daemon#info`.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+pub const EROFS: i32 = 30;
+
+/// Supervisors found dead by the sweep.
+pub static SUPERVISOR_DEATHS: AtomicU64 = AtomicU64::new(0);
+
+/// Mutating requests answered from the idempotency cache.
+pub static IDEM_HITS: AtomicU64 = AtomicU64::new(0);
+
+/// Failed accepts on the control socket: out of fds, harmless, and ones
+/// that needed a rebind.
+pub static ACCEPT_FD_ERRORS: AtomicU64 = AtomicU64::new(0);
+pub static ACCEPT_TRANSIENT_ERRORS: AtomicU64 = AtomicU64::new(0);
+pub static ACCEPT_FATAL_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// Clients parked in `wait` right now.
+pub static WAITERS: AtomicU64 = AtomicU64::new(0);
+
+/// Set by a write failing with EROFS, cleared by the next write that succeeds.
+pub static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Feeds the outcome of a write on BASEPATH into READ_ONLY. Must not log,
+/// the logger itself reports through here. The client's writes pass too,
+/// though only for the EROFS check: nothing reports its flag.
+pub fn track_write<T>(result: std::io::Result<T>) -> std::io::Result<T> {
+    match &result {
+        Ok(_) => READ_ONLY.store(false, Ordering::Relaxed),
+        Err(e) if e.raw_os_error() == Some(EROFS) => {
+            if !READ_ONLY.swap(true, Ordering::Relaxed) {
+                eprintln!("[fs] filesystem read-only");
+            }
+        }
+        Err(_) => (),
+    }
+    result
+}
@@ -0,0 +1,322 @@
+//! Status of one service captured once per request, and every format it is
+//! rendered in.
+//!
+//! All formats are driven by FIELDS. A field says how it appears in each
+//! format, or `None` to leave it out there, so a new field needs a decision
+//! for every format before it compiles.
+
+use std::sync::atomic::Ordering;
+
+use crate::{ArcService, State};
+
+pub struct StatusSnapshot {
+    name: String,
+    allow_run: bool,
+    pid: u32,
+    origin: String,
+    features: Vec<&'static str>,
+    notes: Vec<String>,
+    activity: &'static str,
+    spawns: u64,
+    about: String,
+    /// Failure code, if failed.
+    code: Option<String>,
+}
+
+type Render = fn(&StatusSnapshot) -> String;
+
+struct Field {
+    name: &'static str,
+    /// Piece of the plain status line, with its leading separator.
+    plain: Option<Render>,
+    /// Column of the porcelain line and its value.
+    porcelain: Option<(usize, Render)>,
+    /// Indented line under the plain one with `long`, skipped when empty.
+    long: Option<Render>,
+    /// JSON value under the field's name.
+    json: Option<Render>,
+}
+
+const FIELDS: &[Field] = &[
+    Field {
+        name: "allow-run",
+        plain: Some(|s| format!("[{}]", s.allow_run)),
+        porcelain: None,
+        long: None,
+        json: Some(|s| s.allow_run.to_string()),
+    },
+    Field {
+        name: "pid",
+        plain: Some(|s| format!(" {}", s.pid)),
+        porcelain: Some((2, |s| s.pid.to_string())),
+        long: None,
+        json: Some(|s| s.pid.to_string()),
+    },
+    Field {
+        name: "name",
+        plain: Some(|s| format!(" {}", s.name)),
+        porcelain: Some((0, |s| s.name.clone())),
+        long: None,
+        json: Some(|s| quote(&s.name)),
+    },
+    Field {
+        name: "origin",
+        plain: Some(|s| format!(" {}", s.origin)),
+        porcelain: None,
+        long: None,
+        json: Some(|s| quote(&s.origin)),
+    },
+    Field {
+        name: "features",
+        plain: Some(|s| match s.features.is_empty() {
+            true => String::new(),
+            false => format!(" [{}]", s.features.join(",")),
+        }),
+        porcelain: None,
+        long: None,
+        json: Some(|s| array(s.features.iter().map(|feature| quote(feature)))),
+    },
+    Field {
+        name: "notes",
+        plain: Some(|s| match s.notes.is_empty() {
+            true => String::new(),
+            false => format!(" ({})", s.notes.join(", ")),
+        }),
+        porcelain: None,
+        long: None,
+        json: Some(|s| array(s.notes.iter().map(|note| quote(note)))),
+    },
+    Field {
+        name: "activity",
+        plain: None,
+        porcelain: Some((1, |s| s.activity.to_string())),
+        long: None,
+        json: Some(|s| quote(s.activity)),
+    },
+    Field {
+        name: "spawns",
+        plain: None,
+        porcelain: Some((3, |s| s.spawns.to_string())),
+        long: None,
+        json: Some(|s| s.spawns.to_string()),
+    },
+    Field {
+        name: "about",
+        plain: None,
+        porcelain: None,
+        long: Some(|s| s.about.clone()),
+        json: Some(|s| quote(&s.about)),
+    },
+    Field {
+        name: "code",
+        // the failure in notes already carries it
+        plain: None,
+        porcelain: None,
+        long: None,
+        json: Some(|s| s.code.as_deref().map_or(String::from("null"), quote)),
+    },
+];
+
+/// `text` as a JSON string.
+pub fn quote(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn array(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<String>>().join(", "))
+}
+
+impl StatusSnapshot {
+    pub fn capture(name: &str, service: &ArcService) -> Self {
+        Self {
+            name: name.to_string(),
+            allow_run: service.0.allow_run.load(Ordering::Relaxed),
+            pid: service.0.pid.load(Ordering::Acquire),
+            origin: service.origin().to_string(),
+            features: service.features(),
+            notes: service.notes(),
+            activity: service.activity(),
+            spawns: service.0.spawns.load(Ordering::Relaxed),
+            about: service.0.definition.about(),
+            code: match &service.0.status().state {
+                State::Failed(failure) => Some(failure.code().to_string()),
+                _ => None,
+            },
+        }
+    }
+
+    /// Whether the service passes a `features=f` filter: its running
+    /// incarnation has feature `f`, or lacks it for `-f`. No filter passes all.
+    pub fn passes(&self, filter: Option<&str>) -> bool {
+        let Some(filter) = filter else {
+            return true;
+        };
+        match filter.strip_prefix('-') {
+            Some(feature) => !self.features.contains(&feature),
+            None => self.features.contains(&filter),
+        }
+    }
+
+    /// The `status` line; `status#name` leaves out `skip`, the name asked for.
+    pub fn plain(&self, skip: &[&str]) -> String {
+        FIELDS
+            .iter()
+            .filter(|field| !skip.contains(&field.name))
+            .filter_map(|field| field.plain.map(|render| render(self)))
+            .collect()
+    }
+
+    /// The plain line with the `long` lines under it.
+    pub fn long(&self) -> String {
+        let mut lines = vec![self.plain(&[])];
+        for render in FIELDS.iter().filter_map(|field| field.long) {
+            let line = render(self);
+            if !line.is_empty() {
+                lines.push(format!("    {}", line));
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Space separated porcelain columns in column order.
+    pub fn porcelain(&self) -> String {
+        let mut columns: Vec<(usize, String)> = FIELDS
+            .iter()
+            .filter_map(|field| {
+                field
+                    .porcelain
+                    .map(|(column, render)| (column, render(self)))
+            })
+            .collect();
+        columns.sort_by_key(|(column, _)| *column);
+        columns
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// One JSON object, keys in FIELDS order.
+    pub fn json(&self) -> String {
+        let members: Vec<String> = FIELDS
+            .iter()
+            .filter_map(|field| {
+                field
+                    .json
+                    .map(|render| format!("{}: {}", quote(field.name), render(self)))
+            })
+            .collect();
+        format!("{{{}}}", members.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failed() -> StatusSnapshot {
+        StatusSnapshot {
+            name: String::from("web"),
+            allow_run: false,
+            pid: 0,
+            origin: String::from("manual"),
+            features: vec!["log", "hooks"],
+            notes: vec![
+                String::from("exit status: 1, start limit hit [start-limit]"),
+                String::from("dropped 3 lines"),
+            ],
+            activity: "failed",
+            spawns: 5,
+            about: String::from("Web \"frontend\" <http://localhost/>"),
+            code: Some(String::from("start-limit")),
+        }
+    }
+
+    fn running() -> StatusSnapshot {
+        StatusSnapshot {
+            name: String::from("db"),
+            allow_run: true,
+            pid: 4242,
+            origin: String::from("autostart"),
+            features: vec!["log"],
+            notes: Vec::new(),
+            activity: "active",
+            spawns: 1,
+            about: String::new(),
+            code: None,
+        }
+    }
+
+    // a new field changes every one of these, or says why it doesn't
+
+    #[test]
+    fn plain() {
+        assert_eq!(
+            failed().plain(&[]),
+            "[false] 0 web manual [log,hooks] (exit status: 1, start limit hit [start-limit], dropped 3 lines)"
+        );
+        assert_eq!(running().plain(&[]), "[true] 4242 db autostart [log]");
+        assert_eq!(running().plain(&["name"]), "[true] 4242 autostart [log]");
+    }
+
+    #[test]
+    fn long() {
+        assert_eq!(
+            failed().long(),
+            "[false] 0 web manual [log,hooks] (exit status: 1, start limit hit [start-limit], dropped 3 lines)\n    Web \"frontend\" <http://localhost/>"
+        );
+        assert_eq!(running().long(), "[true] 4242 db autostart [log]");
+    }
+
+    #[test]
+    fn porcelain() {
+        assert_eq!(failed().porcelain(), "web failed 0 5");
+        assert_eq!(running().porcelain(), "db active 4242 1");
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(
+            failed().json(),
+            concat!(
+                r#"{"allow-run": false, "pid": 0, "name": "web", "origin": "manual", "#,
+                r#""features": ["log", "hooks"], "#,
+                r#""notes": ["exit status: 1, start limit hit [start-limit]", "dropped 3 lines"], "#,
+                r#""activity": "failed", "spawns": 5, "#,
+                r#""about": "Web \"frontend\" <http://localhost/>", "code": "start-limit"}"#
+            )
+        );
+        assert_eq!(
+            running().json(),
+            concat!(
+                r#"{"allow-run": true, "pid": 4242, "name": "db", "origin": "autostart", "#,
+                r#""features": ["log"], "notes": [], "activity": "active", "spawns": 1, "#,
+                r#""about": "", "code": null}"#
+            )
+        );
+    }
+
+    #[test]
+    fn passes() {
+        assert!(failed().passes(None));
+        assert!(failed().passes(Some("hooks")));
+        assert!(!running().passes(Some("hooks")));
+        assert!(!failed().passes(Some("-hooks")));
+        assert!(running().passes(Some("-hooks")));
+    }
+
+    #[test]
+    fn quote_escapes() {
+        assert_eq!(quote("a\"b\\c\nd"), r#""a\"b\\c\u000ad""#);
+    }
+}
@@ -0,0 +1,88 @@
+use log::info;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::{TRACE_MAX, TRACE_SEC};
+use crate::logger;
+
+/// Verbs whose payloads never show up in a trace (reserved for token-bearing auth).
+const SENSITIVE: &[&str] = &["auth"];
+
+static UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static REDACT: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn enable() {
+    *UNTIL.lock().unwrap() = Some(Instant::now() + Duration::from_secs(TRACE_SEC));
+    info!("trace: on for {}s", TRACE_SEC);
+}
+
+pub fn disable() {
+    *UNTIL.lock().unwrap() = None;
+    info!("trace: off");
+}
+
+fn active() -> bool {
+    let mut until = UNTIL.lock().unwrap();
+    match *until {
+        Some(deadline) if Instant::now() >= deadline => {
+            *until = None;
+            info!("trace: auto-disabled after {}s", TRACE_SEC);
+            false
+        }
+        Some(_) => true,
+        None => false,
+    }
+}
+
+fn escape(payload: &str) -> String {
+    let escaped: String = payload.escape_debug().collect();
+    match escaped.char_indices().nth(TRACE_MAX) {
+        Some((cut, _)) => format!("{}...({} bytes)", &escaped[..cut], payload.len()),
+        None => escaped,
+    }
+}
+
+/// Assigns the request an id while tracing is on, so every log line of the
+/// connection thread carries it. `mask` hides secrets in the logged message
+/// and only runs while tracing.
+pub fn request(peer: Option<(i32, u32)>, message: &str, mask: impl Fn(&str) -> String) {
+    logger::set_request(None);
+    if !active() {
+        return;
+    }
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    logger::set_request(Some(id));
+
+    let verb = message.split('#').next().unwrap_or_default();
+    let redact = SENSITIVE.contains(&verb);
+    REDACT.with(|cell| cell.set(redact));
+
+    let (pid, uid) = match peer {
+        Some((pid, uid)) => (pid.to_string(), uid.to_string()),
+        None => ("?".to_string(), "?".to_string()),
+    };
+    let payload = match redact {
+        true => format!("{}#<redacted>", verb),
+        false => escape(&mask(message)),
+    };
+    info!("trace: request from pid {} uid {}: {}", pid, uid, payload);
+}
+
+pub fn response(message: &str) {
+    if logger::request().is_none() {
+        return;
+    }
+
+    let payload = match REDACT.with(|cell| cell.get()) {
+        true => String::from("<redacted>"),
+        false => escape(message),
+    };
+    info!("trace: response: {}", payload);
+}
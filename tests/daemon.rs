@@ -0,0 +1,1141 @@
+//! The daemon driven end to end: each test runs its own `--sandbox`ed
+//! daemon over services made of the `dctl-fixture` binary, so the suite
+//! needs no root and never touches the real paths.
+
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DCTL: &str = env!("CARGO_BIN_EXE_dctl");
+const FIXTURE: &str = env!("CARGO_BIN_EXE_dctl-fixture");
+/// For tests about anything but services; a config needs one.
+const IDLE: &[&str] = &["idle FIXTURE"];
+/// Longest any one condition below is waited for.
+const PATIENCE: Duration = Duration::from_secs(20);
+
+struct Sandbox {
+    dir: PathBuf,
+    daemon: Child,
+}
+
+impl Sandbox {
+    /// A daemon over `services`, config lines where `FIXTURE` stands for the
+    /// fixture binary and `DIR` for the sandbox. Nothing is autostarted.
+    fn new(test: &str, services: &[&str]) -> Self {
+        Self::start(prepare(test, services))
+    }
+
+    /// A daemon in `dir`, once it answers.
+    fn start(dir: PathBuf) -> Self {
+        let daemon = Command::new(DCTL)
+            .args(["daemon", "start", &sandbox_flag(&dir)])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let sandbox = Self { dir, daemon };
+        sandbox.until("the daemon to answer", || {
+            sandbox.dctl(&["ping"]).0 == "pong"
+        });
+        sandbox
+    }
+
+    /// Runs the client against this daemon: its output trimmed and its
+    /// exit code.
+    fn dctl(&self, args: &[&str]) -> (String, i32) {
+        let output = Command::new(DCTL)
+            .args(args)
+            .arg(sandbox_flag(&self.dir))
+            .arg("--timeout=15")
+            .output()
+            .unwrap();
+        let text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (text, output.status.code().unwrap_or(-1))
+    }
+
+    fn active(&self, name: &str) -> String {
+        self.dctl(&["is-active", name]).0
+    }
+
+    /// The pid in `status name`, 0 if not running.
+    fn pid(&self, name: &str) -> u32 {
+        let (status, _) = self.dctl(&["status", name]);
+        status
+            .split_whitespace()
+            .nth(1)
+            .and_then(|pid| pid.parse().ok())
+            .unwrap_or(0)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn until(&self, what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + PATIENCE;
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = self.dctl(&["stop"]);
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A fresh sandbox directory for `test` with the config and an empty
+/// autostart file, see `Sandbox::new`.
+fn prepare(test: &str, services: &[&str]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("dctl-{}-{}", test, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let config: String = services
+        .iter()
+        .map(|line| {
+            line.replace("FIXTURE", FIXTURE)
+                .replace("DIR", &dir.to_string_lossy())
+                + "\n"
+        })
+        .collect();
+    std::fs::write(dir.join("config"), config).unwrap();
+    std::fs::write(dir.join("autostart"), "").unwrap();
+    dir
+}
+
+/// Starts a daemon in `dir` that is expected to refuse: its stderr and
+/// exit code.
+fn refused(dir: &Path) -> (String, i32) {
+    let mut daemon = Command::new(DCTL)
+        .args(["daemon", "start", &sandbox_flag(dir)])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let deadline = Instant::now() + PATIENCE;
+    while daemon.try_wait().unwrap().is_none() {
+        if Instant::now() > deadline {
+            let _ = daemon.kill();
+            panic!("the daemon in {} started", dir.display());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let output = daemon.wait_with_output().unwrap();
+    (
+        String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        output.status.code().unwrap_or(-1),
+    )
+}
+
+fn sandbox_flag(dir: &Path) -> String {
+    format!("--sandbox={}", dir.display())
+}
+
+fn read(path: &Path) -> String {
+    std::fs::read_to_string(path).unwrap_or_default()
+}
+
+#[test]
+fn start_and_stop() {
+    let sandbox = Sandbox::new("start-stop", &["svc FIXTURE"]);
+    assert_eq!(sandbox.active("svc"), "inactive");
+
+    sandbox.dctl(&["start", "svc"]);
+    sandbox.until("svc to run", || sandbox.active("svc") == "active");
+    assert_ne!(sandbox.pid("svc"), 0);
+
+    sandbox.dctl(&["stop", "svc"]);
+    assert_eq!(sandbox.active("svc"), "inactive");
+    assert_eq!(sandbox.pid("svc"), 0);
+}
+
+#[test]
+fn unknown_names_are_named_in_the_error() {
+    let sandbox = Sandbox::new("unknown-name", &["svc FIXTURE"]);
+    let (out, _) = sandbox.dctl(&["start", "ghost"]);
+    assert!(out.contains("ghost: not configured"), "{}", out);
+    for verb in ["stop", "restart"] {
+        let (out, _) = sandbox.dctl(&[verb, "ghost"]);
+        assert!(
+            out.contains("service: can't find ghost"),
+            "{}: {}",
+            verb,
+            out
+        );
+    }
+}
+
+#[test]
+fn restart_spawns_anew() {
+    let sandbox = Sandbox::new("restart", &["svc FIXTURE"]);
+    sandbox.dctl(&["start", "svc"]);
+    sandbox.until("svc to run", || sandbox.pid("svc") != 0);
+    let before = sandbox.pid("svc");
+
+    sandbox.dctl(&["restart", "svc"]);
+    sandbox.until("svc to run again", || sandbox.pid("svc") != 0);
+    assert_ne!(sandbox.pid("svc"), before);
+    assert_eq!(sandbox.active("svc"), "active");
+}
+
+#[test]
+fn crash_loop_hits_the_start_limit() {
+    let sandbox = Sandbox::new(
+        "crash-loop",
+        &["crash RestartSec=0 FIXTURE --exit-after=0 --code=3"],
+    );
+    sandbox.dctl(&["start", "crash"]);
+    sandbox.until("crash to give up", || sandbox.active("crash") == "failed");
+
+    let (full, _) = sandbox.dctl(&["status", "crash", "--full"]);
+    assert!(full.contains("failure: start-limit"), "{}", full);
+    let (why, _) = sandbox.dctl(&["why", "crash"]);
+    assert!(why.contains("start limit"), "{}", why);
+}
+
+#[test]
+fn oneshot_passes_its_exit_code_on() {
+    let sandbox = Sandbox::new(
+        "oneshot",
+        &["job Type=oneshot FIXTURE --exit-after=0 --code=7"],
+    );
+    let (_, code) = sandbox.dctl(&["start", "job", "--wait"]);
+    assert_eq!(code, 7);
+    assert_eq!(sandbox.active("job"), "failed");
+}
+
+#[test]
+fn ignored_sigterm_ends_in_sigkill() {
+    let sandbox = Sandbox::new("sigterm", &["stubborn TIMEOUTSTOP=1 FIXTURE --ignore-term"]);
+    sandbox.dctl(&["start", "stubborn"]);
+    sandbox.until("stubborn to run", || sandbox.pid("stubborn") != 0);
+    // the fixture ignores SIGTERM only once it got to run
+    thread::sleep(Duration::from_millis(300));
+
+    let begin = Instant::now();
+    sandbox.dctl(&["stop", "stubborn"]);
+    assert!(begin.elapsed() >= Duration::from_secs(1));
+    assert_eq!(sandbox.active("stubborn"), "inactive");
+    assert!(read(&sandbox.path("daemon.log")).contains("no exit after SIGTERM, killing"));
+}
+
+#[test]
+fn stop_signal_avoids_the_kill() {
+    let sandbox = Sandbox::new(
+        "stopsignal",
+        &["polite STOPSIGNAL=INT TIMEOUTSTOP=10 FIXTURE --ignore-term"],
+    );
+    sandbox.dctl(&["start", "polite"]);
+    sandbox.until("polite to run", || sandbox.pid("polite") != 0);
+    thread::sleep(Duration::from_millis(300));
+
+    let begin = Instant::now();
+    sandbox.dctl(&["stop", "polite"]);
+    assert!(begin.elapsed() < Duration::from_secs(5));
+    assert_eq!(sandbox.active("polite"), "inactive");
+    assert!(!read(&sandbox.path("daemon.log")).contains("killing"));
+}
+
+#[test]
+fn watchdog_ticks_only_while_running() {
+    let sandbox = Sandbox::new("watchdog", &["dog FIXTURE --watchdog=DIR/dog"]);
+    let dog = sandbox.path("dog");
+    sandbox.dctl(&["start", "dog"]);
+    sandbox.until("the first tick", || !read(&dog).is_empty());
+
+    let tick = read(&dog);
+    sandbox.until("another tick", || read(&dog) != tick);
+
+    sandbox.dctl(&["stop", "dog"]);
+    let last = read(&dog);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(read(&dog), last);
+}
+
+#[test]
+fn output_reaches_the_service_log() {
+    let sandbox = Sandbox::new("output", &["chatty FIXTURE --print-rate=50"]);
+    sandbox.dctl(&["start", "chatty"]);
+    sandbox.until("output in the log", || {
+        sandbox
+            .dctl(&["log", "chatty"])
+            .0
+            .contains("fixture: line 10")
+    });
+}
+
+#[test]
+fn stop_does_not_hang_on_a_forked_child() {
+    let sandbox = Sandbox::new("fork", &["parent FIXTURE --fork=DIR/child.pid"]);
+    let pidfile = sandbox.path("child.pid");
+    sandbox.dctl(&["start", "parent"]);
+    sandbox.until("the child pid", || !read(&pidfile).is_empty());
+
+    let begin = Instant::now();
+    sandbox.dctl(&["stop", "parent"]);
+    assert!(begin.elapsed() < Duration::from_secs(8));
+    assert_eq!(sandbox.active("parent"), "inactive");
+
+    let _ = Command::new("kill").arg(read(&pidfile)).status();
+}
+
+#[test]
+fn run_refuses_a_name_outside_the_runtime_dir() {
+    let sandbox = Sandbox::new("run-name", IDLE);
+    let runtime = sandbox.path("run");
+    std::fs::create_dir_all(runtime.join("keep")).unwrap();
+
+    let (response, _) = sandbox.dctl(&["run", "..", FIXTURE, "--exit-after=0"]);
+    assert!(response.contains("bad service name"), "{}", response);
+    assert!(runtime.join("keep").exists());
+}
+
+#[test]
+fn runtime_dir_goes_on_stop_unless_kept() {
+    // written by hand: `prepare` would substitute the DIR in the directive
+    let dir = prepare("keep-runtime", IDLE);
+    let config = format!("plain {0}\nkept KEEP_RUNTIME_DIR=yes {0}\n", FIXTURE);
+    std::fs::write(dir.join("config"), config).unwrap();
+    let sandbox = Sandbox::start(dir);
+    for name in ["plain", "kept"] {
+        sandbox.dctl(&["start", name]);
+        sandbox.until("the service to run", || sandbox.pid(name) != 0);
+        assert!(sandbox.path("run").join(name).is_dir());
+        sandbox.dctl(&["stop", name]);
+    }
+    assert!(!sandbox.path("run/plain").exists());
+    assert!(sandbox.path("run/kept").is_dir());
+    assert!(sandbox.dctl(&["status", "kept"]).0.contains("keep-runtime"));
+}
+
+#[test]
+fn log_refuses_a_path_outside_the_log_dir() {
+    let sandbox = Sandbox::new("log-name", IDLE);
+    std::fs::write(sandbox.path("service.log"), "not yours\n").unwrap();
+
+    let (response, _) = sandbox.dctl(&["log", ".."]);
+    assert!(response.contains("bad service name"), "{}", response);
+    assert!(!response.contains("not yours"));
+}
+
+/// Runs `busy` while asking for status every 200ms, each answer within a
+/// second even though `busy` is stuck in a slow stop; `busy`'s output.
+fn responsive_during(sandbox: &Sandbox, busy: &[&str]) -> String {
+    thread::scope(|scope| {
+        let busy = scope.spawn(|| sandbox.dctl(busy).0);
+        thread::sleep(Duration::from_millis(500));
+        for _ in 0..5 {
+            let begin = Instant::now();
+            let (status, code) = sandbox.dctl(&["status"]);
+            assert_eq!(code, 0, "{}", status);
+            assert!(
+                begin.elapsed() < Duration::from_secs(1),
+                "status took {:?}",
+                begin.elapsed()
+            );
+            thread::sleep(Duration::from_millis(200));
+        }
+        busy.join().unwrap()
+    })
+}
+
+#[test]
+fn status_answers_while_reload_stops_a_service() {
+    let sandbox = Sandbox::new("reload-slow", &["other FIXTURE"]);
+    // added by a reload, enabled: not a manual start, which reload keeps
+    std::fs::write(
+        sandbox.path("config"),
+        format!("other {0}\nslow TIMEOUTSTOP=4 {0} --ignore-term\n", FIXTURE),
+    )
+    .unwrap();
+    std::fs::write(sandbox.path("autostart"), "slow\n").unwrap();
+    sandbox.dctl(&["reload"]);
+    sandbox.until("slow to run", || sandbox.pid("slow") != 0);
+    thread::sleep(Duration::from_millis(300));
+
+    std::fs::write(sandbox.path("config"), format!("other {}\n", FIXTURE)).unwrap();
+    let reloaded = responsive_during(&sandbox, &["reload"]);
+    assert!(reloaded.contains("removed slow"), "{}", reloaded);
+    assert!(sandbox.dctl(&["status", "slow"]).0.contains("can't find"));
+}
+
+#[test]
+fn status_answers_while_a_changed_service_restarts() {
+    let sandbox = Sandbox::new("bounce-slow", &["slow TIMEOUTSTOP=4 FIXTURE --ignore-term"]);
+    sandbox.dctl(&["start", "slow"]);
+    sandbox.until("slow to run", || sandbox.pid("slow") != 0);
+    thread::sleep(Duration::from_millis(300));
+    let before = sandbox.pid("slow");
+
+    std::fs::write(
+        sandbox.path("config"),
+        format!("slow TIMEOUTSTOP=3 {} --ignore-term\n", FIXTURE),
+    )
+    .unwrap();
+    let restarted = responsive_during(&sandbox, &["restart-changed"]);
+    assert!(restarted.contains("restarted slow"), "{}", restarted);
+    sandbox.until("slow to run again", || sandbox.pid("slow") != 0);
+    assert_ne!(sandbox.pid("slow"), before);
+}
+
+#[test]
+fn config_effective_shows_the_sandbox_paths() {
+    let sandbox = Sandbox::new("config", &["svc Typo=1 FIXTURE"]);
+    let (table, code) = sandbox.dctl(&["config", "effective"]);
+    assert_eq!(code, 0);
+    let socket = table
+        .lines()
+        .find(|line| line.starts_with("SOCKET_PATH"))
+        .unwrap();
+    assert_eq!(
+        socket.split_whitespace().nth(1),
+        Some(sandbox.path("daemon.sock").to_str().unwrap())
+    );
+
+    let (json, _) = sandbox.dctl(&["config", "effective", "--json"]);
+    assert!(json.contains("\"name\": \"START_LIMIT\""), "{}", json);
+    assert!(
+        json.contains("{\"service\": \"svc\", \"key\": \"Typo\"}"),
+        "{}",
+        json
+    );
+}
+
+#[test]
+fn secrets_never_leave_the_daemon() {
+    let sandbox = Sandbox::new(
+        "secrets",
+        &[
+            "vault SECRET_ENV=DB_PASS Env=DB_PASS=s3cr3t-db Env=API_TOKEN=t0k3n-api \
+           ONSTART=\"FIXTURE --exit-after=0 s3cr3t-db\" FIXTURE s3cr3t-db",
+        ],
+    );
+    // traces need root; without it the rest is still checked
+    let traced = sandbox.dctl(&["trace:on"]).0 == "trace:on";
+
+    sandbox.dctl(&["start", "vault", "--env=SESSION_KEY=k3y-start"]);
+    sandbox.until("vault to run", || sandbox.pid("vault") != 0);
+    let outputs = [
+        sandbox.dctl(&["show", "vault"]).0,
+        sandbox.dctl(&["show", "vault", "--running"]).0,
+        sandbox.dctl(&["status", "vault", "--full"]).0,
+        sandbox.dctl(&["events"]).0,
+    ];
+    sandbox.dctl(&["stop", "vault"]);
+    if traced {
+        sandbox.dctl(&["trace:off"]);
+    }
+    let log = read(&sandbox.path("daemon.log"));
+    if traced {
+        assert!(log.contains("start#vault"), "no trace in the log");
+    }
+
+    assert!(outputs[0].contains("***"), "{}", outputs[0]);
+    for text in outputs.iter().chain([&log]) {
+        for secret in ["s3cr3t-db", "t0k3n-api", "k3y-start"] {
+            assert!(!text.contains(secret), "{} in:\n{}", secret, text);
+        }
+    }
+}
+
+#[test]
+fn status_json_has_one_object_per_service() {
+    let sandbox = Sandbox::new("status-json", &["a FIXTURE", "b FIXTURE"]);
+    sandbox.dctl(&["start", "a"]);
+    sandbox.until("a to run", || sandbox.pid("a") != 0);
+
+    let (json, code) = sandbox.dctl(&["status", "--json"]);
+    assert_eq!(code, 0);
+    let lines: Vec<&str> = json.lines().collect();
+    assert_eq!(lines.len(), 4, "{}", json);
+    assert!(lines[1].contains("\"name\": \"a\"") && lines[1].contains("\"activity\": \"active\""));
+    assert!(lines[2].contains("\"name\": \"b\"") && lines[2].contains("\"code\": null"));
+}
+
+#[test]
+fn features_filter_every_status_listing() {
+    let sandbox = Sandbox::new(
+        "status-features",
+        &["ext Handles=x-ping FIXTURE", "plain FIXTURE"],
+    );
+    for name in ["ext", "plain"] {
+        sandbox.dctl(&["start", name]);
+        sandbox.until("the service to run", || sandbox.pid(name) != 0);
+    }
+
+    let (table, _) = sandbox.dctl(&["status", "--features=ext"]);
+    assert_eq!(table.lines().count(), 1, "{}", table);
+    assert!(table.contains(" ext "), "{}", table);
+
+    let (long, _) = sandbox.dctl(&["status", "--long", "--features=-ext"]);
+    assert!(
+        long.contains(" plain ") && !long.contains(" ext "),
+        "{}",
+        long
+    );
+
+    let (json, _) = sandbox.dctl(&["status", "--json", "--features=ext"]);
+    assert!(
+        json.contains("\"name\": \"ext\"") && !json.contains("\"plain\""),
+        "{}",
+        json
+    );
+
+    let (many, _) = sandbox.dctl(&["status", "ext,plain,ghost", "--features=ext"]);
+    let lines: Vec<&str> = many.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", many);
+    assert!(lines[0].contains(" ext "), "{}", many);
+    assert_eq!(lines[1], "ghost - unknown");
+}
+
+#[test]
+fn stale_socket_is_replaced() {
+    let dir = prepare("stale-socket", IDLE);
+    drop(UnixListener::bind(dir.join("daemon.sock")).unwrap());
+
+    let sandbox = Sandbox::start(dir);
+    assert!(read(&sandbox.path("daemon.log")).contains("removing stale"));
+}
+
+#[test]
+fn second_daemon_leaves_the_first_alone() {
+    let sandbox = Sandbox::new("live-socket", IDLE);
+    let (stderr, code) = refused(&sandbox.dir);
+    assert_eq!(code, 1);
+    assert!(stderr.contains("already running"), "{}", stderr);
+    assert_eq!(sandbox.dctl(&["ping"]).0, "pong");
+}
+
+#[test]
+fn foreign_files_are_not_removed() {
+    let dir = prepare("foreign-file", IDLE);
+    let socket = dir.join("daemon.sock");
+    std::fs::write(&socket, "someone's data").unwrap();
+    let (stderr, code) = refused(&dir);
+    assert_eq!(code, 1);
+    assert!(stderr.contains("is a regular file"), "{}", stderr);
+    assert_eq!(read(&socket), "someone's data");
+
+    std::fs::remove_file(&socket).unwrap();
+    std::fs::create_dir(&socket).unwrap();
+    let (stderr, _) = refused(&dir);
+    assert!(stderr.contains("is a directory"), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn socket_that_wont_answer_is_not_removed() {
+    let dir = prepare("foreign-socket", IDLE);
+    let socket = dir.join("daemon.sock");
+    // bound and never accepted from: not the daemon, and not stale
+    let _listener = UnixListener::bind(&socket).unwrap();
+    std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(0o000)).unwrap();
+
+    let (stderr, code) = refused(&dir);
+    assert_eq!(code, 1);
+    // root connects anyway and finds nobody speaking the protocol
+    assert!(
+        stderr.contains("Permission denied") || stderr.contains("belongs to another program"),
+        "{}",
+        stderr
+    );
+    assert!(socket.exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn missing_parent_directory_is_named() {
+    let dir = std::env::temp_dir().join(format!("dctl-no-parent-{}", std::process::id()));
+    let (stderr, code) = refused(&dir);
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains(&format!(
+            "parent directory {} does not exist",
+            dir.display()
+        )),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn socket_path_too_long_is_refused() {
+    let dir = prepare(&"long".repeat(30), IDLE);
+    let (stderr, code) = refused(&dir);
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains("a unix socket path takes at most 107"),
+        "{}",
+        stderr
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// A fake daemon in `dir` answering one request with `response`, then
+/// dropping the connection; runs the client with `args` against it.
+fn against_fake(test: &str, response: &'static str, args: &[&str]) -> (String, String, i32) {
+    let dir = prepare(test, IDLE);
+    let listener = UnixListener::bind(dir.join("daemon.sock")).unwrap();
+    let fake = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = String::new();
+        stream.read_to_string(&mut request).unwrap();
+        // the write may race the client going away, which is the point
+        let _ = stream.write_all(response.as_bytes());
+        request
+    });
+
+    let output = Command::new(DCTL)
+        .args(args)
+        .arg(sandbox_flag(&dir))
+        .arg("--timeout=15")
+        .output()
+        .unwrap();
+    let request = fake.join().unwrap();
+    assert!(!request.is_empty());
+    let _ = std::fs::remove_dir_all(&dir);
+    (
+        String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        output.status.code().unwrap_or(-1),
+    )
+}
+
+#[test]
+fn response_cut_off_mid_write_is_reported() {
+    let (stdout, stderr, code) = against_fake(
+        "truncated",
+        "[true] 101 web manual [log]\n[true] 10",
+        &["status"],
+    );
+    assert_eq!(code, 3);
+    assert!(stderr.contains("response: truncated"), "{}", stderr);
+    // nothing of the half table is printed
+    assert_eq!(stdout, "");
+}
+
+#[test]
+fn response_with_its_marker_is_printed() {
+    let (stdout, _, code) =
+        against_fake("complete", "[true] 101 web manual [log]\u{4}", &["status"]);
+    assert_eq!(code, 0);
+    assert_eq!(stdout, "[true] 101 web manual [log]");
+}
+
+#[test]
+fn daemon_killed_mid_request_is_reported() {
+    let mut sandbox = Sandbox::new("killed", &["slow TIMEOUTSTOP=10 FIXTURE --ignore-term"]);
+    sandbox.dctl(&["start", "slow"]);
+    sandbox.until("slow to run", || sandbox.pid("slow") != 0);
+    let orphan = sandbox.pid("slow");
+    thread::sleep(Duration::from_millis(300));
+
+    let stop = Command::new(DCTL)
+        .args(["stop", "slow", &sandbox_flag(&sandbox.dir)])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    sandbox.daemon.kill().unwrap();
+    let output = stop.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("response: truncated"));
+
+    let _ = Command::new("kill")
+        .args(["-KILL", &orphan.to_string()])
+        .status();
+}
+
+#[test]
+fn interrupted_wait_leaves_no_waiter() {
+    let sandbox = Sandbox::new(
+        "wait-abort",
+        &["flaky RestartSec=30 FIXTURE --exit-after=0 --code=1"],
+    );
+    sandbox.dctl(&["start", "flaky"]);
+    sandbox.until("flaky to back off", || {
+        sandbox.active("flaky") == "activating"
+    });
+
+    let waiters = || {
+        let (info, _) = sandbox.dctl(&["info"]);
+        info.lines()
+            .find_map(|line| line.strip_prefix("waiters: ")?.parse::<u32>().ok())
+            .unwrap()
+    };
+    let mut wait = Command::new(DCTL)
+        .args(["wait", "flaky", &sandbox_flag(&sandbox.dir)])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    sandbox.until("the waiter", || waiters() == 1);
+
+    Command::new("kill")
+        .args(["-INT", &wait.id().to_string()])
+        .status()
+        .unwrap();
+    let _ = wait.wait();
+    sandbox.until("the waiter to go", || waiters() == 0);
+}
+
+/// One request straight over the socket, without a client process.
+fn raw(sandbox: &Sandbox, message: &str) -> String {
+    let mut stream = std::os::unix::net::UnixStream::connect(sandbox.path("daemon.sock")).unwrap();
+    stream.write_all(message.as_bytes()).unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    response
+}
+
+#[test]
+fn a_client_hanging_up_early_is_only_a_warning() {
+    let sandbox = Sandbox::new("hang-up", &["slow TIMEOUTSTOP=1 FIXTURE --ignore-term"]);
+    sandbox.dctl(&["start", "slow"]);
+    sandbox.until("slow to run", || sandbox.pid("slow") != 0);
+
+    let mut stream = std::os::unix::net::UnixStream::connect(sandbox.path("daemon.sock")).unwrap();
+    stream.write_all(b"stop#slow").unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    drop(stream);
+
+    sandbox.until("the reply to fail", || {
+        read(&sandbox.path("daemon.log")).contains("message: bad send")
+    });
+    assert_eq!(sandbox.dctl(&["ping"]).0, "pong");
+}
+
+/// Total bytes of the `memory:` line of `daemon#info`.
+fn footprint(sandbox: &Sandbox) -> usize {
+    let info = raw(sandbox, "daemon#info");
+    info.lines()
+        .find_map(|line| {
+            line.strip_prefix("memory: ")?
+                .split('b')
+                .next()?
+                .parse()
+                .ok()
+        })
+        .unwrap()
+}
+
+#[test]
+#[ignore = "soak: thousands of requests, about two minutes"]
+fn footprint_stays_bounded_under_load() {
+    let sandbox = Sandbox::new(
+        "soak",
+        &[
+            "svc FIXTURE",
+            "crash RestartSec=0 FIXTURE --exit-after=0 --code=1",
+        ],
+    );
+    let round = |i: usize| {
+        let padding = "p".repeat(i % 97 * 31);
+        let messages = [
+            format!("status#svc?key=k{}", i),
+            String::from("daemon#status?long"),
+            format!("show#svc?running&key={}", padding),
+            format!("start#svc?env=PAD={}&arg={}&key=s{}", padding, padding, i),
+            format!("stop#svc?key=t{}", i),
+            format!(
+                "run#job{}?exec={}&arg=--exit-after=0&arg={}",
+                i % 50,
+                FIXTURE,
+                "p".repeat(i % 50 * 31)
+            ),
+            format!("bogus-{}#{}", i, padding),
+            String::from("log#svc?lines=5"),
+            String::from("daemon#events"),
+            format!("cancel-retry#crash?key=c{}", i),
+        ];
+        for message in &messages {
+            raw(&sandbox, message);
+        }
+        // now and then one request far bigger than the rest
+        if i.is_multiple_of(100) {
+            raw(&sandbox, &format!("status#{}", "x".repeat(1 << 20)));
+            raw(&sandbox, "start#crash");
+        }
+    };
+
+    // past the idempotency, event and job caps before the first sample
+    for i in 0..100 {
+        round(i);
+    }
+    let warm = footprint(&sandbox);
+    for i in 100..400 {
+        round(i);
+    }
+    let soaked = footprint(&sandbox);
+    assert!(
+        soaked <= warm + warm / 10 + 4096,
+        "footprint grew from {}b to {}b",
+        warm,
+        soaked
+    );
+}
+
+/// `why name` once it mentions `expected`.
+fn why_until(sandbox: &Sandbox, name: &str, expected: &str) -> String {
+    let mut why = String::new();
+    sandbox.until(expected, || {
+        why = sandbox.dctl(&["why", name]).0;
+        why.contains(expected)
+    });
+    why
+}
+
+#[test]
+fn why_tells_a_stopped_service_apart() {
+    let sandbox = Sandbox::new(
+        "why-stopped",
+        &["svc FIXTURE", "done Restart=never FIXTURE --exit-after=0"],
+    );
+    why_until(&sandbox, "svc", "never started since the daemon came up");
+
+    sandbox.dctl(&["start", "svc"]);
+    sandbox.until("svc to run", || sandbox.pid("svc") != 0);
+    why_until(&sandbox, "svc", "running as pid");
+    sandbox.dctl(&["stop", "svc"]);
+    why_until(&sandbox, "svc", "stopped on request");
+
+    sandbox.dctl(&["start", "done"]);
+    why_until(
+        &sandbox,
+        "done",
+        "last spawn ended: exit status: 0, Restart=never",
+    );
+}
+
+#[test]
+fn why_names_a_failed_exec() {
+    let sandbox = Sandbox::new("why-exec", &["missing DIR/missing"]);
+    sandbox.dctl(&["start", "missing"]);
+    let why = why_until(&sandbox, "missing", "(exec-not-found)");
+    assert!(why.contains("not restarted until started again"), "{}", why);
+}
+
+#[test]
+fn why_counts_the_backoff() {
+    let sandbox = Sandbox::new(
+        "why-backoff",
+        &["crash RestartSec=30 FIXTURE --exit-after=0 --code=1"],
+    );
+    sandbox.dctl(&["start", "crash"]);
+    let why = why_until(
+        &sandbox,
+        "crash",
+        "crashed quickly, backoff: attempt 1 of 5 in",
+    );
+    assert!(why.contains("event: "), "{}", why);
+}
+
+#[test]
+fn why_waits_out_restart_sec_after_a_run_that_lasted() {
+    let sandbox = Sandbox::new(
+        "why-restart",
+        &["lasting RestartSec=30 FIXTURE --exit-after=1.5 --code=1"],
+    );
+    sandbox.dctl(&["start", "lasting"]);
+    why_until(&sandbox, "lasting", "exited, restarting in");
+    let (why, _) = sandbox.dctl(&["why", "lasting"]);
+    assert!(why.contains("(RestartSec=30)"), "{}", why);
+}
+
+#[test]
+fn why_gives_the_start_limit() {
+    let sandbox = Sandbox::new(
+        "why-limit",
+        &["crash RestartSec=0 FIXTURE --exit-after=0 --code=1"],
+    );
+    sandbox.dctl(&["start", "crash"]);
+    let why = why_until(&sandbox, "crash", "(start-limit)");
+    assert!(
+        why.contains("start limit: crashed 5 times in a row"),
+        "{}",
+        why
+    );
+}
+
+#[test]
+fn why_follows_requires_to_the_failed_dependency() {
+    let sandbox = Sandbox::new(
+        "why-deps",
+        &[
+            "web Requires=api FIXTURE",
+            "api Requires=db FIXTURE",
+            "db DIR/missing",
+        ],
+    );
+    sandbox.dctl(&["start", "db"]);
+    sandbox.until("db to fail", || sandbox.active("db") == "failed");
+
+    let (why, _) = sandbox.dctl(&["why", "web"]);
+    assert!(why.contains("blocked by db: "), "{}", why);
+    assert!(why.contains("(web → api → db)"), "{}", why);
+}
+
+#[test]
+fn why_says_when_autostart_leaves_it_out() {
+    let dir = prepare("why-enabled", &["on FIXTURE", "off FIXTURE"]);
+    std::fs::write(dir.join("autostart"), "on\n").unwrap();
+    let sandbox = Sandbox::start(dir);
+    sandbox.until("on to run", || sandbox.pid("on") != 0);
+
+    let (why, _) = sandbox.dctl(&["why", "off"]);
+    assert!(why.contains("not enabled"), "{}", why);
+    let (why, _) = sandbox.dctl(&["why", "on"]);
+    assert!(!why.contains("not enabled"), "{}", why);
+}
+
+#[test]
+fn why_marks_a_transient_run() {
+    let sandbox = Sandbox::new("why-transient", IDLE);
+    sandbox.dctl(&["run", "job", FIXTURE]);
+    why_until(&sandbox, "job", "transient, forgotten once it stops");
+}
+
+#[test]
+fn service_starts_with_no_stray_fds() {
+    let sandbox = Sandbox::new("fds", &["svc FIXTURE --fds=DIR/fds"]);
+    sandbox.dctl(&["start", "svc"]);
+    let listing = sandbox.path("fds");
+    sandbox.until("the fd listing", || listing.exists());
+    let listing = read(&listing);
+
+    let fds: Vec<&str> = listing
+        .lines()
+        .filter_map(|line| line.split(' ').next())
+        .collect();
+    assert_eq!(fds, ["0", "1", "2"], "{}", listing);
+}
+
+#[test]
+fn service_starts_with_default_signals() {
+    // grep leaves its dispositions alone, the fixture's Rust runtime would
+    // ignore SIGPIPE, as the daemon's does
+    let sandbox = Sandbox::new("signals", &["sigs /bin/grep ^Sig /proc/self/status"]);
+    sandbox.dctl(&["start", "sigs"]);
+    let mut log = String::new();
+    sandbox.until("the signal state in the log", || {
+        log = sandbox.dctl(&["log", "sigs"]).0;
+        log.contains("SigCgt:")
+    });
+
+    assert!(log.contains("SigBlk:\t0000000000000000"), "{}", log);
+    assert!(log.contains("SigIgn:\t0000000000000000"), "{}", log);
+}
+
+/// The client run as a hook would run it, with HOOK_ENV set: stdout,
+/// stderr and exit code.
+fn dctl_in_hook(sandbox: &Sandbox, args: &[&str]) -> (String, String, i32) {
+    let output = Command::new(DCTL)
+        .args(args)
+        .arg(sandbox_flag(&sandbox.dir))
+        .env("DCTL_IN_HOOK", "1")
+        .output()
+        .unwrap();
+    (
+        String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        output.status.code().unwrap_or(-1),
+    )
+}
+
+#[test]
+fn hook_queries_status_while_its_start_is_watched() {
+    let dir = prepare(
+        "hook-status",
+        &["svc ONSTART=\"/bin/sh DIR/hook.sh\" FIXTURE"],
+    );
+    let flag = sandbox_flag(&dir);
+    let script = [
+        format!("{} status svc {} > {}/status", DCTL, flag, dir.display()),
+        format!("echo \"status $?\" >> {}/hook.partial", dir.display()),
+        format!("{} restart svc {} 2> {}/restart", DCTL, flag, dir.display()),
+        format!("echo \"restart $?\" >> {}/hook.partial", dir.display()),
+        format!(
+            "echo \"env $DCTL_IN_HOOK\" >> {}/hook.partial",
+            dir.display()
+        ),
+        format!("mv {0}/hook.partial {0}/hook", dir.display()),
+    ];
+    std::fs::write(dir.join("hook.sh"), script.join("\n") + "\n").unwrap();
+    let sandbox = Sandbox::start(dir);
+
+    // --wait watches the new process for a while before it answers
+    let (_, code) = sandbox.dctl(&["start", "svc", "--wait"]);
+    assert_eq!(code, 0);
+    let hook = read(&sandbox.path("hook"));
+    assert_eq!(hook, "status 0\nrestart 2\nenv 1\n");
+    // up already, as seen from inside its own ONSTART
+    let status = read(&sandbox.path("status"));
+    assert!(status.starts_with("[true] "), "{}", status);
+    assert!(!status.starts_with("[true] 0 "), "{}", status);
+    assert!(read(&sandbox.path("restart")).contains("from a hook can deadlock the daemon"));
+}
+
+#[test]
+fn mutating_from_a_hook_needs_allow_reentrant() {
+    let sandbox = Sandbox::new("hook-reentrant", &["svc FIXTURE"]);
+
+    let (_, stderr, code) = dctl_in_hook(&sandbox, &["start", "svc"]);
+    assert_eq!(code, 2);
+    assert!(
+        stderr
+            .contains("option: start from a hook can deadlock the daemon, pass --allow-reentrant"),
+        "{}",
+        stderr
+    );
+    assert_eq!(sandbox.active("svc"), "inactive");
+
+    let (status, _, code) = dctl_in_hook(&sandbox, &["status", "svc"]);
+    assert_eq!(code, 0);
+    assert!(status.starts_with("[false] 0 "), "{}", status);
+
+    let (_, _, code) = dctl_in_hook(&sandbox, &["start", "svc", "--allow-reentrant"]);
+    assert_eq!(code, 0);
+    sandbox.until("svc to run", || sandbox.pid("svc") != 0);
+}
+
+#[test]
+fn hook_that_hangs_is_killed() {
+    let sandbox = Sandbox::new("hook-timeout", &["svc ONSTART=\"/bin/sleep 60\" FIXTURE"]);
+    sandbox.dctl(&["start", "svc"]);
+    let log = sandbox.path("daemon.log");
+    sandbox.until("the hook to be killed", || {
+        read(&log).contains("hook: svc ONSTART: killed after 10s")
+    });
+    assert_eq!(sandbox.active("svc"), "active");
+}
+
+/// A network namespace made with `ip netns`, holding a bridge `dctl0`;
+/// deleted on drop.
+struct Netns(String);
+
+impl Netns {
+    /// None where `ip netns` can't make one, as without root.
+    fn add(test: &str) -> Option<Self> {
+        let name = format!("dctl-{}-{}", test, std::process::id());
+        let ip = |args: &[&str]| {
+            Command::new("ip")
+                .args(args)
+                .stderr(Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success())
+        };
+        if !ip(&["netns", "add", &name]) {
+            return None;
+        }
+        let netns = Netns(name);
+        ip(&["-n", &netns.0, "link", "add", "dctl0", "type", "bridge"]).then_some(netns)
+    }
+}
+
+impl Drop for Netns {
+    fn drop(&mut self) {
+        let _ = Command::new("ip").args(["netns", "del", &self.0]).status();
+    }
+}
+
+#[test]
+fn service_sees_the_interfaces_of_its_netns() {
+    let Some(netns) = Netns::add("netns") else {
+        eprintln!("skipped: ip netns unavailable");
+        return;
+    };
+    let sandbox = Sandbox::new(
+        "netns",
+        &[
+            &format!("peek NETNS={} /bin/cat /proc/net/dev", netns.0),
+            &format!("inside NETNS={} FIXTURE", netns.0),
+        ],
+    );
+
+    sandbox.dctl(&["start", "peek"]);
+    let mut log = String::new();
+    sandbox.until("peek's interfaces in its log", || {
+        log = sandbox.dctl(&["log", "peek"]).0;
+        log.contains("dctl0:")
+    });
+    let interfaces: Vec<&str> = log
+        .lines()
+        .filter_map(|line| Some(line.split_once(':')?.0.trim()))
+        .filter(|name| !name.contains(' ') && !name.contains('|'))
+        .collect();
+    assert_eq!(interfaces, ["lo", "dctl0"], "{}", log);
+
+    sandbox.dctl(&["start", "inside"]);
+    sandbox.until("inside to run", || sandbox.pid("inside") != 0);
+    let (show, _) = sandbox.dctl(&["show", "inside", "--running"]);
+    let line = format!("netns: {} (net:[", netns.0);
+    assert!(show.contains(&line), "{}", show);
+}
+
+#[test]
+fn shutdown_stops_dependents_first_whatever_the_config_order() {
+    let sandbox = Sandbox::new(
+        "shutdown-order",
+        &[
+            "web Requires=db FIXTURE --as=web",
+            "cache FIXTURE --as=cache",
+            "db FIXTURE --as=db",
+        ],
+    );
+    for name in ["db", "web", "cache"] {
+        sandbox.dctl(&["start", name]);
+        sandbox.until("it to run", || sandbox.pid(name) != 0);
+    }
+
+    let (report, code) = sandbox.dctl(&["daemon", "stop"]);
+    assert_eq!(code, 0, "{}", report);
+    assert!(report.starts_with("order: cache, web → db\n"), "{}", report);
+    let log = read(&sandbox.path("daemon.log"));
+    let ended = |name: &str| {
+        log.find(&format!("terminate: {} --as={}", FIXTURE, name))
+            .unwrap()
+    };
+    assert!(ended("web") < ended("db"), "{}", log);
+}
+
+#[test]
+fn shutdown_stops_a_group_in_parallel() {
+    let sandbox = Sandbox::new(
+        "shutdown-parallel",
+        &[
+            "one TIMEOUTSTOP=2 FIXTURE --ignore-term",
+            "two TIMEOUTSTOP=2 FIXTURE --ignore-term",
+        ],
+    );
+    for name in ["one", "two"] {
+        sandbox.dctl(&["start", name]);
+        sandbox.until("it to run", || sandbox.pid(name) != 0);
+    }
+    thread::sleep(Duration::from_millis(300));
+
+    let begin = Instant::now();
+    let (report, _) = sandbox.dctl(&["daemon", "stop"]);
+    assert!(
+        begin.elapsed() < Duration::from_secs(4),
+        "{:?}",
+        begin.elapsed()
+    );
+    assert!(
+        report.starts_with("order: one, two\nkilled: "),
+        "{}",
+        report
+    );
+}